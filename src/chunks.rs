@@ -0,0 +1,331 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use messages::{Algorithm, DecodeError, Hash, Message, Payload, Pushable};
+
+/// How many bytes each chunk holds
+/// A chunk must fit in a single PUT datagram
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// How many chunks a manifest can list
+/// The manifest itself must fit in a single PUT datagram
+pub const MAX_CHUNKS: usize = 1900;
+
+/// Marks a payload as a manifest
+const MAGIC: &[u8; 8] = b"DHTCHNK2";
+
+/// A chunk and its hash
+pub type Chunk = (Hash, Vec<u8>);
+
+/// Describes an object split in chunks
+/// Chunks are stored under their content-addressed hash, so every node checks them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Object length, in bytes
+    pub len: u64,
+    /// Content-addressed hash of the whole object
+    pub digest: Hash,
+    /// Hashes of the chunks, in order
+    pub chunks: Vec<Hash>,
+}
+
+impl Manifest {
+    /// Read a manifest from a payload
+    /// Returns None if the payload is a plain value
+    pub fn from_payload(payload: &Payload) -> Option<Manifest> {
+        Manifest::pull(&payload.0).ok()
+    }
+
+    /// Put the chunks back together, checking them against the manifest
+    pub fn assemble(&self, chunks: Vec<Vec<u8>>) -> Result<Vec<u8>, ChunkError> {
+        if chunks.len() != self.chunks.len() {
+            return Err(ChunkError::MissingChunks);
+        }
+
+        let mut data = Vec::with_capacity(chunks.iter().map(Vec::len).sum());
+        for (hash, chunk) in self.chunks.iter().zip(chunks) {
+            if !hash.matches(&chunk) {
+                return Err(ChunkError::CorruptedChunk(*hash));
+            }
+            data.extend(chunk);
+        }
+
+        if data.len() as u64 != self.len || !self.digest.matches(&data) {
+            return Err(ChunkError::CorruptedObject);
+        }
+        Ok(data)
+    }
+}
+
+/// Read a hash from a manifest, along with its length
+/// Only content-addressed hashes can be checked, any other one is refused
+fn pull_hash(buf: &[u8]) -> Result<(Hash, usize), DecodeError> {
+    let hash = Hash::pull(buf)?;
+    if !hash.is_content() {
+        return Err(DecodeError::InvalidContent);
+    }
+    Ok((hash, hash.frame_len()))
+}
+
+impl Pushable for Manifest {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        frame.extend_from_slice(MAGIC);
+        frame.extend_from_slice(&self.len.to_be_bytes());
+        self.digest.push_in_frame(frame);
+        frame.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+        for hash in &self.chunks {
+            hash.push_in_frame(frame);
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        MAGIC.len() + 8 + self.digest.frame_len() + 4
+            + self.chunks.iter().map(Pushable::frame_len).sum::<usize>()
+    }
+
+    fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.get(..MAGIC.len()) != Some(&MAGIC[..]) {
+            return Err(DecodeError::InvalidContent);
+        }
+        let mut buf = &buf[MAGIC.len()..];
+
+        let mut len = [0; 8];
+        len.copy_from_slice(buf.get(..8).ok_or(DecodeError::MessageTooShort)?);
+        let len = u64::from_be_bytes(len);
+        buf = &buf[8..];
+
+        let (digest, size) = pull_hash(buf)?;
+        buf = &buf[size..];
+
+        let count = buf.get(..4).ok_or(DecodeError::MessageTooShort)?;
+        let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as usize;
+        buf = &buf[4..];
+
+        let mut chunks = Vec::with_capacity(count.min(MAX_CHUNKS));
+        for _ in 0..count {
+            let (hash, size) = pull_hash(buf)?;
+            buf = &buf[size..];
+            chunks.push(hash);
+        }
+
+        if !buf.is_empty() {
+            return Err(DecodeError::MessageTooLong);
+        }
+        // The chunks can't hold more than they are listed for
+        if len > (chunks.len() * CHUNK_SIZE) as u64 {
+            return Err(DecodeError::InvalidContent);
+        }
+
+        Ok(Manifest {
+            len,
+            digest,
+            chunks,
+        })
+    }
+}
+
+/// Split an object in chunks, hashed with the given algorithm
+/// Returns the manifest describing the object along with the chunks and their hashes
+pub fn split(algorithm: Algorithm, data: &[u8]) -> io::Result<(Manifest, Vec<Chunk>)> {
+    let chunks: Vec<_> = data.chunks(CHUNK_SIZE)
+        .map(|chunk| (Hash::of_content(algorithm, chunk), chunk.to_vec()))
+        .collect();
+    if chunks.len() > MAX_CHUNKS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "value too large to be chunked",
+        ));
+    }
+
+    let manifest = Manifest {
+        len: data.len() as u64,
+        digest: Hash::of_content(algorithm, data),
+        chunks: chunks.iter().map(|&(hash, _)| hash).collect(),
+    };
+    Ok((manifest, chunks))
+}
+
+/// Prepare a value to be put
+/// Values too large for a single datagram are split in chunks, and so are the ones that would be
+/// read as a manifest, so that they are read back as they were put. Returns the payload to put
+/// under the value hash, along with the PUT messages storing the chunks, to be sent first
+fn prepare(
+    algorithm: Algorithm,
+    data: Vec<u8>,
+    ttl: Option<u32>,
+) -> io::Result<(Payload, Vec<Message>)> {
+    if data.len() <= CHUNK_SIZE && Manifest::pull(&data).is_err() {
+        return Ok((Payload(data), Vec::new()));
+    }
+
+    let (manifest, chunks) = split(algorithm, &data)?;
+    let mut frame = Vec::with_capacity(manifest.frame_len());
+    manifest.push_in_frame(&mut frame);
+
+    let messages = chunks
+        .into_iter()
+        .map(|(chunk_hash, chunk)| Message::Put(chunk_hash, Payload(chunk), ttl))
+        .collect();
+    Ok((Payload(frame), messages))
+}
+
+/// The PUT messages storing a value under a hash
+/// Large values are put in chunks first, and the hash holds their manifest
+pub fn put_messages(hash: Hash, data: Vec<u8>, ttl: Option<u32>) -> io::Result<Vec<Message>> {
+    let (payload, mut messages) = prepare(Algorithm::default(), data, ttl)?;
+    messages.push(Message::Put(hash, payload, ttl));
+    Ok(messages)
+}
+
+/// The PUT messages storing a value under its content-addressed hash, and that hash
+/// The hash of a large value is the one of its manifest
+pub fn put_content(
+    algorithm: Algorithm,
+    data: Vec<u8>,
+    ttl: Option<u32>,
+) -> io::Result<(Hash, Vec<Message>)> {
+    let (payload, mut messages) = prepare(algorithm, data, ttl)?;
+    let hash = Hash::of_content(algorithm, &payload.0);
+    messages.push(Message::Put(hash, payload, ttl));
+    Ok((hash, messages))
+}
+
+/// Why an object could not be put back together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// Some chunks could not be fetched
+    MissingChunks,
+    /// A chunk does not match its hash
+    CorruptedChunk(Hash),
+    /// The chunks do not add up to the object described by the manifest
+    CorruptedObject,
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChunkError::MissingChunks => fmt.write_str("some chunks are missing"),
+            ChunkError::CorruptedChunk(hash) => write!(fmt, "chunk {:?} is corrupted", hash),
+            ChunkError::CorruptedObject => fmt.write_str("the chunks do not match the manifest"),
+        }
+    }
+}
+
+impl Error for ChunkError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        put_content, put_messages, split, ChunkError, Manifest, CHUNK_SIZE, MAX_CHUNKS,
+    };
+    use messages::{Algorithm, Hash, Message, Payload, Pushable, MAX_PAYLOAD};
+
+    #[test]
+    fn roundtrip() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 42).map(|i| i as u8).collect();
+        let (manifest, chunks) = split(Algorithm::Sha256, &data).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(manifest.len, data.len() as u64);
+
+        let mut frame = Vec::new();
+        manifest.push_in_frame(&mut frame);
+        assert_eq!(frame.len(), manifest.frame_len());
+        assert_eq!(Manifest::from_payload(&Payload(frame)), Some(manifest.clone()));
+
+        let contents: Vec<_> = chunks.into_iter().map(|(_, chunk)| chunk).collect();
+        assert_eq!(manifest.assemble(contents.clone()), Ok(data));
+
+        // Chunks are checked against their hash
+        let mut corrupted = contents.clone();
+        corrupted[1][0] ^= 1;
+        assert_eq!(
+            manifest.assemble(corrupted),
+            Err(ChunkError::CorruptedChunk(manifest.chunks[1]))
+        );
+        assert_eq!(
+            manifest.assemble(contents[..2].to_vec()),
+            Err(ChunkError::MissingChunks)
+        );
+
+        // Manifests claiming more than their chunks can hold, or listing hashes that can't be
+        // checked, are refused
+        let mut frame = Vec::new();
+        Manifest {
+            len: u64::MAX,
+            chunks: Vec::new(),
+            ..manifest.clone()
+        }.push_in_frame(&mut frame);
+        assert_eq!(Manifest::from_payload(&Payload(frame)), None);
+        let mut frame = Vec::new();
+        Manifest {
+            chunks: vec![Hash::from(42); 3],
+            ..manifest
+        }.push_in_frame(&mut frame);
+        assert_eq!(Manifest::from_payload(&Payload(frame)), None);
+    }
+
+    #[test]
+    fn plain_values() {
+        // Small values are put as is
+        let hash = Hash::from(42);
+        assert_eq!(
+            put_messages(hash, b"hello".to_vec(), None).unwrap(),
+            vec![Message::Put(hash, Payload(b"hello".to_vec()), None)]
+        );
+        assert_eq!(Manifest::from_payload(&Payload(b"hello".to_vec())), None);
+
+        // Large ones are put after their chunks
+        let messages = put_messages(hash, vec![0; CHUNK_SIZE + 1], Some(60)).unwrap();
+        assert_eq!(messages.len(), 3);
+        match messages[2] {
+            Message::Put(last, ref payload, Some(60)) => {
+                assert_eq!(last, hash);
+                assert_eq!(Manifest::from_payload(payload).unwrap().chunks.len(), 2);
+            }
+            ref msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // Values that would be read as a manifest are put in a chunk too, and read back as is
+        let mut frame = Vec::new();
+        Manifest {
+            len: 0,
+            digest: Hash::of_content(Algorithm::Sha256, b""),
+            chunks: Vec::new(),
+        }.push_in_frame(&mut frame);
+        let messages = put_messages(hash, frame.clone(), None).unwrap();
+        assert_eq!(messages.len(), 2);
+        let manifest = match messages[1] {
+            Message::Put(_, ref payload, None) => Manifest::from_payload(payload).unwrap(),
+            ref msg => panic!("unexpected message {:?}", msg),
+        };
+        match messages[0] {
+            Message::Put(_, ref chunk, None) => {
+                assert_eq!(manifest.assemble(vec![chunk.0.clone()]), Ok(frame));
+            }
+            ref msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // Content-addressed values are put under the hash of what is actually stored
+        let data = vec![0; CHUNK_SIZE + 1];
+        let (content, messages) = put_content(Algorithm::Blake3, data, None).unwrap();
+        assert_eq!(content.algorithm(), Some(Algorithm::Blake3));
+        for msg in &messages {
+            match *msg {
+                Message::Put(hash, ref payload, None) => assert!(hash.matches(&payload.0)),
+                ref msg => panic!("unexpected message {:?}", msg),
+            }
+        }
+        assert_eq!(messages.len(), 3);
+
+        // Chunks and full manifests fit in a PUT
+        let manifest = Manifest {
+            len: 0,
+            digest: content,
+            chunks: vec![content; MAX_CHUNKS],
+        };
+        assert!(manifest.frame_len() <= MAX_PAYLOAD);
+        let chunk = Message::Put(content, Payload(vec![0; CHUNK_SIZE]), Some(60));
+        assert!(chunk.serialize().is_ok());
+    }
+}
@@ -1,29 +1,45 @@
+// structopt-derive 0.1 generates its impls inside a private const
+#![allow(non_local_definitions)]
+
+use std::error::Error;
 use std::process;
+use std::fs;
 use std::io;
 use std::thread;
 use std::iter;
 use std::str::FromStr;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::path::PathBuf;
 
 use shlex;
 use structopt::StructOpt;
-use futures::{Future, Sink, Stream};
+use futures::{stream, Future, Sink, Stream};
 use futures::sync::mpsc::channel;
 use tokio_core::reactor::Handle;
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
 
-use state::State;
-use messages::{Hash, Message, Payload};
+use chunks;
+use routing::Partitioning;
+use server::Protocol;
+use state::{Origin, State};
+use storage::Eviction;
+use messages::{Algorithm, Hash, Message, Payload, RequestId};
 
+/// The addresses a host name resolves to, and how to reach them
+/// Written as udp://host:port or tcp://host:port, UDP being used without a scheme
 #[derive(Debug)]
-pub struct Addrs(pub Vec<SocketAddr>);
+pub struct Addrs(pub Protocol, pub Vec<SocketAddr>);
 
 impl FromStr for Addrs {
     type Err = io::Error;
     fn from_str(src: &str) -> Result<Addrs, io::Error> {
-        Ok(Addrs(src.to_socket_addrs()?.collect()))
+        let (protocol, host) = match src.split_once("://") {
+            Some((protocol, host)) => (protocol.parse().map_err(invalid)?, host),
+            None => (Protocol::Udp, src),
+        };
+        Ok(Addrs(protocol, host.to_socket_addrs()?.collect()))
     }
 }
 
@@ -40,26 +56,94 @@ pub enum ClientCommand {
     #[structopt(name = "put", display_order_raw = "2")]
     /// PUT a hash
     Put {
-        /// The hash to put
-        hash: Hash,
+        /// The hash to put, or the payload with --content
+        hash: Option<String>,
         /// The payload to send
-        payload: Payload,
+        payload: Option<Payload>,
+        #[structopt(long = "file")]
+        /// Send the content of a file instead, split in chunks if needed
+        file: Option<PathBuf>,
+        #[structopt(long = "ttl")]
+        /// How many seconds the hash should be kept
+        ttl: Option<u32>,
+        #[structopt(long = "content")]
+        /// Put the payload under the hash of its content, and print that hash
+        content: bool,
+        #[structopt(long = "algorithm", default_value = "sha256")]
+        /// How the hash is computed with --content: sha256 or blake3
+        algorithm: Algorithm,
+    },
+    #[structopt(name = "delete", display_order_raw = "3")]
+    /// DELETE a hash
+    Delete {
+        /// The hash to delete
+        hash: Hash,
     },
-    #[structopt(name = "discover", display_order_raw = "3")]
+    #[structopt(name = "discover", display_order_raw = "4")]
     /// DISCOVER a peer
     Discover {
         /// The peer address
         address: SocketAddr,
     },
+    #[structopt(name = "watch", display_order_raw = "5")]
+    /// WATCH a hash, showing every change until interrupted
+    Watch {
+        /// The hash to watch
+        hash: Hash,
+    },
+}
+
+/// An error about the arguments of a command
+fn invalid<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
 }
 
 impl ClientCommand {
-    pub fn to_message(self) -> Message {
-        match self {
-            ClientCommand::Get { hash } => Message::Get(hash),
-            ClientCommand::Put { hash, payload } => Message::Put(hash, payload),
-            ClientCommand::Discover { address } => Message::Discover(address),
-        }
+    /// The messages to send, in order
+    /// Large payloads are sent in several chunks
+    pub fn to_messages(self) -> io::Result<Vec<Message>> {
+        Ok(match self {
+            ClientCommand::Get { hash } => vec![Message::Get(hash)],
+            ClientCommand::Put {
+                hash,
+                payload,
+                file,
+                ttl,
+                content: true,
+                algorithm,
+            } => {
+                // There is no hash to give, so the first argument is the payload
+                let data = match (hash, payload, file) {
+                    (Some(payload), None, None) => Payload::from_str(&payload)?.0,
+                    (None, None, Some(path)) => fs::read(path)?,
+                    _ => return Err(invalid("expected either a payload or a file")),
+                };
+                chunks::put_content(algorithm, data, ttl)?.1
+            }
+            ClientCommand::Put {
+                hash,
+                payload,
+                file,
+                ttl,
+                content: false,
+                ..
+            } => {
+                let hash = match hash {
+                    Some(hash) => Hash::from_str(&hash).map_err(invalid)?,
+                    None => return Err(invalid("expected a hash")),
+                };
+                let data = match (payload, file) {
+                    (Some(Payload(data)), None) => data,
+                    (None, Some(path)) => fs::read(path)?,
+                    _ => return Err(invalid("expected either a payload or a file")),
+                };
+                chunks::put_messages(hash, data, ttl)?
+            }
+            ClientCommand::Delete { hash } => vec![Message::Delete(hash)],
+            ClientCommand::Discover { address } => vec![Message::Discover(address)],
+            // The lease has to be renewed, see `client::watch`
+            ClientCommand::Watch { hash } => vec![Message::Watch(hash)],
+        })
     }
 }
 
@@ -70,20 +154,56 @@ pub enum CLI {
     /// Act as a server
     Server {
         #[structopt(default_value = "[::]:0")]
-        /// The address the server should listen to
-        bind: Addrs,
+        /// The addresses the server should listen to, prefixed by tcp:// for TCP
+        bind: Vec<Addrs>,
+        #[structopt(long = "reply-errors")]
+        /// Answer invalid messages with an ERROR
+        reply_errors: bool,
+        #[structopt(long = "mode", default_value = "kademlia")]
+        /// How hashes are spread between nodes: kademlia, ring or flood
+        mode: Partitioning,
+        #[structopt(long = "replicas", default_value = "3")]
+        /// How many nodes store each hash
+        replicas: usize,
+        #[structopt(long = "read-quorum", default_value = "1")]
+        /// How many replicas must answer a GET
+        read_quorum: usize,
+        #[structopt(long = "write-quorum", default_value = "1")]
+        /// How many replicas must hold a PUT before it is acknowledged
+        write_quorum: usize,
+        #[structopt(long = "data-dir")]
+        /// Keep the hashes in this directory instead of in memory
+        data_dir: Option<PathBuf>,
+        #[structopt(long = "max-bytes")]
+        /// Evict hashes when the in-memory store holds more bytes than this
+        max_bytes: Option<usize>,
+        #[structopt(long = "max-entries")]
+        /// Evict hashes when the in-memory store holds more hashes than this
+        max_entries: Option<usize>,
+        #[structopt(long = "eviction", default_value = "lru")]
+        /// Which hashes are evicted first: lru or lfu
+        eviction: Eviction,
+        #[structopt(long = "unix")]
+        /// Also listen to local clients on this Unix socket
+        unix: Option<PathBuf>,
+        #[structopt(long = "http")]
+        /// Also serve an HTTP gateway on this address
+        http: Option<SocketAddr>,
     },
     #[structopt(name = "client")]
     /// Send a request to a server
     Client {
-        /// The host:port to connect to
-        connect: Addrs,
+        /// The host:port to connect to, prefixed by tcp:// for TCP
+        connect: Option<Addrs>,
+        #[structopt(long = "unix")]
+        /// Connect to the Unix socket of a local server instead
+        unix: Option<PathBuf>,
         #[structopt(subcommand)] command: ClientCommand,
     },
 }
 
 /// Show a prompt to directly interract with the server state, using the client subcommand
-pub fn prompt<'a>(state: &'a State, handle: &'a Handle) -> Box<Future<Item = (), Error = ()> + 'a> {
+pub fn prompt<'a>(state: &'a State, handle: &'a Handle) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
     // Server responses are sent through this channel
     let (sender, receiver) = channel(1);
 
@@ -96,12 +216,12 @@ pub fn prompt<'a>(state: &'a State, handle: &'a Handle) -> Box<Future<Item = (),
             Ok(line) => {
                 let app = ClientCommand::clap();
                 let args = shlex::split(&line).unwrap();
-                if args.len() == 0 {
+                if args.is_empty() {
                     continue;
                 }
                 rl.add_history_entry(&line);
                 match app.get_matches_from_safe(
-                    iter::once(String::from("client")).chain(args.into_iter()),
+                    iter::once(String::from("client")).chain(args),
                 ) {
                     Ok(matches) => {
                         sender
@@ -128,22 +248,41 @@ pub fn prompt<'a>(state: &'a State, handle: &'a Handle) -> Box<Future<Item = (),
         }
     });
 
-    let (sender2, receiver2) = channel::<Message>(10);
+    let (sender2, receiver2) = channel::<(Option<RequestId>, Message)>(10);
     // Process each messages from prompt, and pipe the response in a new channel
-    let pipe_future = receiver.for_each(move |value| {
+    // The messages of a command share an ID, which tells their responses apart
+    let mut last_id: RequestId = 0;
+    let pipe_future = receiver.for_each(move |value: ClientCommand| {
+        let messages = match value.to_messages() {
+            Ok(messages) => messages,
+            Err(e) => {
+                println!("{}", e);
+                return Ok(());
+            }
+        };
+        last_id += 1;
+        let id = last_id;
+        println!("Request #{}", id);
+        let responses: Vec<_> = messages
+            .into_iter()
+            .map(|msg| state.process(msg, Some(id), Origin::Local))
+            .collect();
         let f = sender2
             .clone()
             .sink_map_err(|_| ())
-            .send_all(state.process(value.to_message()))
+            .send_all(stream::iter_ok::<_, ()>(responses).flatten())
             .map(|_| ());
         handle.spawn(f);
         Ok(())
     });
 
     // Print each responses
-    let messages_future = receiver2.for_each(|msg| {
+    let messages_future = receiver2.for_each(|(request, msg)| {
         // TODO: pretty print messages
-        println!("Response: {:?}", msg);
+        match request {
+            Some(id) => println!("Response to #{}: {:?}", id, msg),
+            None => println!("Response: {:?}", msg),
+        }
         Ok(())
     });
     Box::new(pipe_future.join(messages_future).map(|_| ()))
@@ -1,68 +1,411 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use futures::{Future, Sink, Stream};
-use tokio_core::net::UdpSocket;
+use std::path::PathBuf;
+use std::time::Duration;
+use futures::future::{self, Loop};
+use futures::{stream, Future, IntoFuture, Sink, Stream};
+use tokio_core::net::{TcpStream, UdpSocket};
 use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Timer;
+use tokio_uds::UnixStream;
 
-use messages::{Message, UdpMessage};
+use chunks::Manifest;
+use messages::{Hash, Message, Payload, RequestId, TcpMessage, UdpMessage};
+use server::{Decoded, Protocol};
+use state::WATCH_LEASE;
 
-/// Send a request to a server
-/// The returned future resolves when the request is fullfilled
+/// Where a server is reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// The Unix socket of a local server
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn new(protocol: Protocol, addr: SocketAddr) -> Self {
+        match protocol {
+            Protocol::Udp => Endpoint::Udp(addr),
+            Protocol::Tcp => Endpoint::Tcp(addr),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Udp(addr) => write!(fmt, "udp://{}", addr),
+            Endpoint::Tcp(addr) => write!(fmt, "tcp://{}", addr),
+            Endpoint::Unix(ref path) => write!(fmt, "{}", path.display()),
+        }
+    }
+}
+
+/// Send requests to a server, one after the other
+/// The returned future resolves when every request is fullfilled, or as soon as one fails
 pub fn request(
-    server: &SocketAddr,
-    req: Message,
+    server: &Endpoint,
+    reqs: Vec<Message>,
     handle: &Handle,
-) -> Box<Future<Item = (), Error = ()>> {
-    // Bind on either the v6 or the v4 wildcard address based on server's address
-    let bind: SocketAddr = if server.is_ipv4() {
-        SocketAddr::from(SocketAddrV4::new(Ipv4Addr::from(0), 0))
-    } else if server.is_ipv6() {
-        SocketAddr::from(SocketAddrV6::new(Ipv6Addr::from([0; 8]), 0, 0, 0))
-    } else {
-        panic!("Address isn't v4 nor v6")
-    };
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let server = server.clone();
+    let handle = handle.clone();
+    let count = reqs.len();
+    let requests = stream::iter_ok(reqs.into_iter().enumerate());
+    Box::new(requests.for_each(move |(i, req)| {
+        // Only the last request is reported, the previous ones store the chunks of a large value
+        let last = i + 1 == count;
+        let handle = handle.clone();
+        let server = server.clone();
+        exchange(&server, req.clone(), &handle)
+            .and_then(move |responses| report(&server, &req, responses, last, &handle))
+    }))
+}
+
+/// Watch a hash, getting and showing its new value every time it is put, until the server goes
+/// away
+/// The lease is renewed with the same request ID, so that the notifications keep coming
+/// Servers only send notifications over connections, UDP servers are not even asked
+pub fn watch(
+    server: &Endpoint,
+    hash: Hash,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    if let Endpoint::Udp(_) = *server {
+        println!("Watching needs a TCP or Unix socket connection, not {}", server);
+        return Box::new(future::err(()));
+    }
+
+    let id = request_id();
+    let server = server.clone();
+    let handle = handle.clone();
+    let renewals = Timer::default()
+        .interval(Duration::from_secs(WATCH_LEASE / 3))
+        .map_err(io::Error::from);
+    let renewals = stream::once(Ok(()))
+        .chain(renewals)
+        .map(move |()| (Some(id), Message::Watch(hash)));
+
+    let src = server.clone();
+    let connected = connect(&server, &handle)
+        .map_err(move |e| println!("Could not connect to {}: {}", src, e));
+    Box::new(connected.and_then(move |(output_sink, input_stream)| {
+        let renewed = output_sink
+            .send_all(renewals)
+            .map(|_| ())
+            .map_err(move |e| println!("Could not watch {:?}: {}", hash, e));
 
-    let socket = UdpSocket::bind(&bind, handle).expect("Could not bind socket");
-
-    let (output_sink, input_stream) = socket.framed(UdpMessage).split();
-
-    // Send the message through the socket
-    let send_future = output_sink
-        .send((*server, req.clone()))
-        .map_err(|e| error!("Could not send message: {}", e))
-        .map(|_| ());
-
-    // Wait until a valid response arrives
-    // GET waits for a PUT response
-    // PUT waits for a IHAVE response
-    // DISCOVER waits for any response (KEEPALIVE…)
-    let recv_future = input_stream
-        .filter(move |&(_, ref resp)| {
-            match req {
-                Message::Get(hash) => {
-                    if let &Message::Put(hash2, ref payload) = resp {
-                        // The server answered with the hash I wanted
-                        if hash == hash2 {
-                            println!("{}", payload);
-                            return true;
-                        }
+        let mut watching = false;
+        let src = server.clone();
+        let notified = input_stream
+            .map_err(move |e| error!("Error exchanging messages with {}: {}", src, e))
+            .filter_map(|resp| resp.ok())
+            .filter(move |&(request, _)| request == Some(id))
+            .for_each(move |(_, resp)| -> Box<dyn Future<Item = (), Error = ()>> {
+                match resp {
+                    // Renewals are answered too
+                    Message::Watch(_) if !watching => {
+                        watching = true;
+                        println!("Watching {:?}", hash);
                     }
-                }
-                Message::Put(hash, _) => {
-                    if let &Message::IHave(hash2) = resp {
-                        // The server has the hash I just pushed
-                        if hash == hash2 {
-                            return true;
-                        }
+                    // Get the new value and show it the way GET would, and keep watching if it
+                    // is invalid
+                    Message::IHave(_) => {
+                        let get = Message::Get(hash);
+                        let (server, handle) = (server.clone(), handle.clone());
+                        let shown = exchange(&server, get.clone(), &handle).and_then(
+                            move |responses| report(&server, &get, responses, true, &handle),
+                        );
+                        return Box::new(shown.then(|_| Ok(())));
+                    }
+                    Message::Delete(_) => println!("Hash {:?} deleted", hash),
+                    Message::Error(code, reason) => {
+                        println!("Error ({:?}): {}", code, reason);
+                        return Box::new(future::err(()));
                     }
+                    _ => (),
                 }
-                Message::Discover(_) => return true,
-                _ => unimplemented!(),
+                Box::new(future::ok(()))
+            })
+            .and_then(|()| {
+                println!("The server closed the connection");
+                Err(())
+            });
+        renewed.select(notified).map(|_| ()).map_err(|_| ())
+    }))
+}
+
+/// Where messages are sent to a server, along with the ID of their request
+type Requests = Box<dyn Sink<SinkItem = (Option<RequestId>, Message), SinkError = io::Error>>;
+
+/// What a server sends back
+type Responses = Box<dyn Stream<Item = Decoded, Error = io::Error>>;
+
+/// Open a channel to a server
+fn connect(
+    server: &Endpoint,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (Requests, Responses), Error = io::Error>> {
+    match *server {
+        Endpoint::Udp(server) => {
+            // Bind on either the v6 or the v4 wildcard address based on server's address
+            let bind: SocketAddr = if server.is_ipv4() {
+                SocketAddr::from(SocketAddrV4::new(Ipv4Addr::from(0), 0))
+            } else if server.is_ipv6() {
+                SocketAddr::from(SocketAddrV6::new(Ipv6Addr::from([0; 8]), 0, 0, 0))
+            } else {
+                panic!("Address isn't v4 nor v6")
             };
-            false
+            let socket = UdpSocket::bind(&bind, handle).expect("Could not bind socket");
+            let (output_sink, input_stream) = socket.framed(UdpMessage).split();
+            // Messages that can't be encoded fail here, instead of leaving as empty datagrams
+            let output_sink = output_sink.with(move |(request, msg): (Option<RequestId>, Message)| {
+                let frame = msg.serialize_for(0, request)?;
+                Ok((server, frame))
+            });
+            let input_stream = input_stream.map(|(_, resp)| resp);
+            Box::new(future::ok((
+                Box::new(output_sink) as Requests,
+                Box::new(input_stream) as Responses,
+            )))
+        }
+        Endpoint::Tcp(server) => Box::new(TcpStream::connect(&server, handle).map(framed)),
+        Endpoint::Unix(ref path) => {
+            Box::new(UnixStream::connect(path, handle).into_future().map(framed))
+        }
+    }
+}
+
+/// Frame the messages sent over a stream, in the version every server understands
+fn framed<S: AsyncRead + AsyncWrite + 'static>(stream: S) -> (Requests, Responses) {
+    let (output_sink, input_stream) = stream.framed(TcpMessage).split();
+    let output_sink = output_sink.with(|(request, msg): (Option<RequestId>, Message)| {
+        msg.serialize_for(0, request).map_err(io::Error::from)
+    });
+    (Box::new(output_sink), Box::new(input_stream))
+}
+
+/// Pick a random request ID
+fn request_id() -> RequestId {
+    // RandomState is seeded randomly for each process
+    RandomState::new().build_hasher().finish() as RequestId
+}
+
+/// Send a request and gather its responses, until the one ending the exchange
+/// GET waits for a PUT followed by a QUORUM, or a NOTFOUND response
+/// PUT and DELETE wait for a QUORUM response
+/// DISCOVER waits for any response (KEEPALIVE…)
+/// Any request stops on an ERROR response
+fn exchange(
+    server: &Endpoint,
+    req: Message,
+    handle: &Handle,
+) -> Box<dyn Future<Item = Vec<Message>, Error = ()>> {
+    // Send the message, with an ID the responses will carry
+    // Messages too large to be sent are reported before connecting
+    let id = request_id();
+    if let Err(e) = req.serialize_for(0, Some(id)) {
+        println!("Could not send request: {}", e);
+        return Box::new(future::err(()));
+    }
+    let sent = req.clone();
+    let input_stream = connect(server, handle)
+        .and_then(move |(output_sink, input_stream)| {
+            output_sink.send((Some(id), sent)).map(|_| input_stream)
         })
-        .take(1) // I only need one valid response
-        .collect()
-        .map_err(|e| error!("Error processing message: {}", e));
+        .flatten_stream();
+
+    // Only keep the valid responses to this request
+    let related = req.clone();
+    let src = server.clone();
+    let server = server.clone();
+    let responses: Box<dyn Stream<Item = Message, Error = ()>> = Box::new(
+        input_stream
+            .filter_map(move |resp| match resp {
+                Ok(resp) => Some(resp),
+                Err(e) => {
+                    warn!("Invalid message from {}: {}", src, e);
+                    None
+                }
+            })
+            .filter(move |&(request, ref resp)| answers(&related, id, request, resp))
+            .map(|(_, resp)| resp)
+            .map_err(move |e| error!("Error exchanging messages with {}: {}", server, e)),
+    );
+
+    let recv_future = future::loop_fn((responses, Vec::new()), move |(responses, mut received)| {
+        let req = req.clone();
+        responses
+            .into_future()
+            .map_err(|(e, _)| e)
+            .map(move |(resp, responses)| match resp {
+                Some(resp) => {
+                    let done = ends(&req, &resp);
+                    received.push(resp);
+                    if done {
+                        Loop::Break(received)
+                    } else {
+                        Loop::Continue((responses, received))
+                    }
+                }
+                None => Loop::Break(received),
+            })
+    });
+
+    Box::new(recv_future)
+}
+
+/// Check if a message answers a request
+/// Servers echo the ID of the request, the responses of older ones are matched on their content
+fn answers(req: &Message, id: RequestId, request: Option<RequestId>, resp: &Message) -> bool {
+    if let Some(request) = request {
+        return request == id;
+    }
+    match (req, resp) {
+        (_, &Message::Error(..)) => true,
+        (&Message::Get(hash), &Message::Put(hash2, _, _))
+        | (&Message::Get(hash), &Message::Quorum(hash2, _, _))
+        | (&Message::Get(hash), &Message::NotFound(hash2))
+        | (&Message::Put(hash, _, _), &Message::Quorum(hash2, _, _))
+        | (&Message::Delete(hash), &Message::Quorum(hash2, _, _)) => hash == hash2,
+        (&Message::Discover(_), _) => true,
+        _ => false,
+    }
+}
+
+/// Check if a message is the last answer to a request
+fn ends(req: &Message, resp: &Message) -> bool {
+    match *resp {
+        Message::Error(..) | Message::Quorum(..) | Message::NotFound(..) => true,
+        _ => matches!(*req, Message::Discover(_)),
+    }
+}
+
+/// Show the responses to a request
+/// Fails if the request failed, and fetches the chunks of large values
+fn report(
+    server: &Endpoint,
+    req: &Message,
+    responses: Vec<Message>,
+    last: bool,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let mut payload = None;
+    let mut quorum = None;
+    for resp in responses {
+        match resp {
+            Message::Error(code, reason) => {
+                println!("Error ({:?}): {}", code, reason);
+                return Box::new(future::err(()));
+            }
+            Message::NotFound(hash) => {
+                // The server gave up looking for the hash
+                println!("Hash {:?} not found", hash);
+                return Box::new(future::err(()));
+            }
+            // The server answered with the hash I wanted
+            Message::Put(_, data, _) => payload = Some(data),
+            // The server tells how many replicas agreed
+            Message::Quorum(_, replicas, required) => quorum = Some((replicas, required)),
+            _ => (),
+        }
+    }
 
-    Box::new(recv_future.join(send_future).map(|_| ()))
+    let (operation, hash) = match *req {
+        Message::Get(hash) => ("Read", hash),
+        Message::Put(hash, _, _) => {
+            if last && hash.is_content() {
+                // The hash was derived from the content, the user needs it to get it back
+                println!("{:?}", hash);
+            }
+            match quorum {
+                Some((replicas, required)) if last => report_quorum("Write", replicas, required),
+                // Enough replicas must have each chunk for the value to be readable
+                Some((replicas, required)) if replicas < required => {
+                    println!("Could not store chunk {:?}", hash);
+                    report_quorum("Write", replicas, required);
+                    return Box::new(future::err(()));
+                }
+                _ => (),
+            }
+            return Box::new(future::ok(()));
+        }
+        Message::Delete(_) => {
+            if let Some((replicas, required)) = quorum {
+                report_quorum("Delete", replicas, required);
+            }
+            return Box::new(future::ok(()));
+        }
+        _ => return Box::new(future::ok(())),
+    };
+
+    let payload = match (payload, quorum) {
+        (Some(payload), _) => payload,
+        // The lookup timed out before any replica answered
+        (None, Some((replicas, required))) => {
+            println!("Hash {:?} not found in time", hash);
+            report_quorum(operation, replicas, required);
+            return Box::new(future::err(()));
+        }
+        (None, None) => return Box::new(future::ok(())),
+    };
+    // Don't trust the server with content-addressed hashes
+    if !hash.matches(&payload.0) {
+        println!("Payload does not match hash {:?}", hash);
+        return Box::new(future::err(()));
+    }
+    let value: Box<dyn Future<Item = Payload, Error = ()>> = match Manifest::from_payload(&payload)
+    {
+        Some(manifest) => fetch(server, manifest, handle),
+        None => Box::new(future::ok(payload)),
+    };
+    Box::new(value.map(move |value| {
+        println!("{}", value);
+        if let Some((replicas, required)) = quorum {
+            report_quorum(operation, replicas, required);
+        }
+    }))
+}
+
+/// Fetch the chunks listed in a manifest and put the value back together
+fn fetch(
+    server: &Endpoint,
+    manifest: Manifest,
+    handle: &Handle,
+) -> Box<dyn Future<Item = Payload, Error = ()>> {
+    let server = server.clone();
+    let handle = handle.clone();
+    let chunks = stream::iter_ok(manifest.chunks.clone()).and_then(move |hash: Hash| {
+        exchange(&server, Message::Get(hash), &handle).and_then(move |responses| {
+            responses
+                .into_iter()
+                .filter_map(|resp| match resp {
+                    Message::Put(_, Payload(data), _) => Some(data),
+                    _ => None,
+                })
+                .next()
+                .ok_or_else(|| println!("Chunk {:?} not found", hash))
+        })
+    });
+
+    Box::new(chunks.collect().and_then(move |chunks| {
+        manifest
+            .assemble(chunks)
+            .map(Payload)
+            .map_err(|e| println!("Could not read the value: {}", e))
+    }))
+}
+
+/// Tell whether enough replicas answered a request
+fn report_quorum(operation: &str, replicas: u8, required: u8) {
+    if replicas >= required {
+        println!("{} quorum reached: {}/{} replicas", operation, replicas, required);
+    } else {
+        println!("{} quorum not reached: {}/{} replicas", operation, replicas, required);
+    }
 }
@@ -0,0 +1,814 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use messages::{Hash, Pushable};
+use storage::{Stats, Storage, MAX_TOMBSTONES};
+
+/// Name of the log inside the data directory
+static LOG_FILE: &str = "hashes.log";
+
+/// Name of the log being written during a compaction
+static COMPACT_FILE: &str = "hashes.log.compact";
+
+/// Don't bother compacting logs with less dead bytes than this
+static COMPACT_THRESHOLD: u64 = 1024 * 1024;
+
+/// Starts the log, followed by the version of its layout
+static LOG_MAGIC: &[u8] = b"DHTLOG";
+
+/// Version of the layout of the records written by this node
+const LOG_VERSION: u8 = 2;
+
+/// Length of the magic and the version starting the log
+const LOG_HEADER_SIZE: usize = 6 + 1;
+
+/// Checksum, kind, expiry time, time to live and content length, followed by the hash and the
+/// content
+const HEADER_SIZE: usize = 4 + 1 + 8 + 4 + 4;
+
+/// Kind of a record storing a hash content
+const RECORD_PUT: u8 = 0;
+
+/// Kind of a record pushing back the expiry time of the previous content of a hash
+const RECORD_REFRESH: u8 = 1;
+
+/// Kind of a record deleting a hash, kept as a tombstone until it expires
+const RECORD_DELETE: u8 = 2;
+
+/// The magic and the version starting the log
+fn log_header() -> Vec<u8> {
+    let mut header = LOG_MAGIC.to_vec();
+    header.push(LOG_VERSION);
+    header
+}
+
+/// CRC-32 (IEEE) of a buffer
+fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in buf {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Encode a record
+/// The checksum covers everything following it, so a torn write is detected on recovery
+fn encode(kind: u8, hash: &Hash, expires: SystemTime, ttl: Duration, data: &[u8]) -> Vec<u8> {
+    let ttl = ttl.as_secs().min(u64::from(u32::MAX)) as u32;
+    let mut record = Vec::with_capacity(HEADER_SIZE + hash.frame_len() + data.len());
+    record.extend_from_slice(&[0; 4]);
+    record.push(kind);
+    record.extend_from_slice(&unix_time(expires).to_be_bytes());
+    record.extend_from_slice(&ttl.to_be_bytes());
+    record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    hash.push_in_frame(&mut record);
+    record.extend_from_slice(data);
+    let crc = crc32(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+    record
+}
+
+/// Where a hash content lies in the log
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Offset of the record
+    offset: u64,
+    /// Length of the record
+    size: u64,
+    /// Length of the content, at the end of the record
+    len: u32,
+    /// Checksum of the content, to tell a new one from the stored one without reading it
+    crc: u32,
+    /// When this hash stops being kept
+    expires: SystemTime,
+    /// How long a refresh keeps it
+    ttl: Duration,
+}
+
+impl Entry {
+    /// How long this content will still be kept
+    fn remaining(&self) -> Duration {
+        self.expires
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    /// Check if content is considered as stale
+    fn is_stale(&self) -> bool {
+        self.expires < SystemTime::now()
+    }
+}
+
+/// The length of a record, read from its head
+fn record_size(head: &[u8]) -> Option<u64> {
+    if head.len() < HEADER_SIZE + 2 {
+        return None;
+    }
+
+    let mut word = [0; 4];
+    word.copy_from_slice(&head[(HEADER_SIZE - 4)..HEADER_SIZE]);
+    let hash_len = 2 + head[HEADER_SIZE + 1] as usize;
+    Some((HEADER_SIZE + hash_len) as u64 + u64::from(u32::from_be_bytes(word)))
+}
+
+/// Parse the record at the start of a buffer, if it is complete and valid
+fn parse(buf: &[u8], offset: u64) -> Option<(u8, Hash, Entry)> {
+    if buf.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let kind = buf[4];
+    if kind != RECORD_PUT && kind != RECORD_REFRESH && kind != RECORD_DELETE {
+        return None;
+    }
+
+    let mut word = [0; 4];
+    let mut long = [0; 8];
+    long.copy_from_slice(&buf[5..13]);
+    let expires = UNIX_EPOCH.checked_add(Duration::from_secs(u64::from_be_bytes(long)))?;
+    word.copy_from_slice(&buf[13..17]);
+    let ttl = Duration::from_secs(u64::from(u32::from_be_bytes(word)));
+    word.copy_from_slice(&buf[17..HEADER_SIZE]);
+    let len = u32::from_be_bytes(word);
+    let hash = Hash::pull(&buf[HEADER_SIZE..]).ok()?;
+    let end = HEADER_SIZE + hash.frame_len() + len as usize;
+    if buf.len() < end {
+        return None;
+    }
+
+    word.copy_from_slice(&buf[..4]);
+    if u32::from_be_bytes(word) != crc32(&buf[4..end]) {
+        return None;
+    }
+
+    let entry = Entry {
+        offset,
+        size: end as u64,
+        len,
+        crc: crc32(&buf[(end - len as usize)..end]),
+        expires,
+        ttl,
+    };
+    Some((kind, hash, entry))
+}
+
+/// Stores hashes in an append-only log inside a directory
+/// Every PUT or DELETE appends a record to the log, and an index of the latest record of each
+/// hash is kept in memory. The index is rebuilt from the log when the store is opened, dropping a
+/// torn record left at its end by a crash. Overwritten and stale records are reclaimed by
+/// compacting the log once they take more room than the live ones
+/// Refreshes only move expiry times, and are written all at once on the next cleanup
+pub struct DiskStore {
+    dir: PathBuf,
+    file: File,
+    index: HashMap<Hash, Entry>,
+    /// The delete records of the deleted hashes
+    tombstones: HashMap<Hash, Entry>,
+    /// The tombstones in expiry order
+    expiries: BTreeSet<(SystemTime, Hash)>,
+    /// The hashes refreshed since the last cleanup
+    refreshed: HashSet<Hash>,
+    /// Size of the log
+    size: u64,
+    /// Size of the records that are not indexed anymore
+    dead: u64,
+}
+
+impl fmt::Debug for DiskStore {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DiskStore")
+            .field("dir", &self.dir)
+            .field("hashes", &self.index.len())
+            .field("tombstones", &self.tombstones.len())
+            .field("size", &self.size)
+            .field("dead", &self.dead)
+            .finish()
+    }
+}
+
+impl DiskStore {
+    /// Open the store in a directory, creating it if needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        // A compaction was interrupted: the log itself is still complete
+        let _ = fs::remove_file(dir.join(COMPACT_FILE));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let mut store = DiskStore {
+            dir,
+            file,
+            index: HashMap::new(),
+            tombstones: HashMap::new(),
+            expiries: BTreeSet::new(),
+            refreshed: HashSet::new(),
+            size: 0,
+            dead: 0,
+        };
+        store.load()?;
+        Ok(store)
+    }
+
+    /// Check the version of the log and rebuild the index
+    /// Logs of unknown versions are refused, rather than being mistaken for corrupted ones
+    fn load(&mut self) -> io::Result<()> {
+        let path = self.dir.join(LOG_FILE);
+        let mut header = Vec::new();
+        (&self.file).seek(SeekFrom::Start(0))?;
+        (&self.file).take(LOG_HEADER_SIZE as u64).read_to_end(&mut header)?;
+
+        if log_header().starts_with(&header) && header.len() < LOG_HEADER_SIZE {
+            // A new log, or one whose header was torn while it was being created
+            self.file.set_len(0)?;
+            self.file.write_all(&log_header())?;
+            self.file.sync_all()?;
+            self.size = LOG_HEADER_SIZE as u64;
+            return Ok(());
+        }
+
+        if !header.starts_with(LOG_MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a log", path.display()),
+            ));
+        }
+        let version = header[LOG_MAGIC.len()];
+        if version != LOG_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has an unknown version {}", path.display(), version),
+            ));
+        }
+        self.recover()
+    }
+
+    /// Rebuild the index from the records of the log
+    /// An invalid record reaching the end of the log is dropped, as a write torn by a crash:
+    /// anywhere else, or if a valid record still follows it because its length was corrupted,
+    /// the log is corrupted and is left untouched
+    fn recover(&mut self) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        let mut log = BufReader::new(self.file.try_clone()?);
+        log.seek(SeekFrom::Start(LOG_HEADER_SIZE as u64))?;
+
+        let mut offset = LOG_HEADER_SIZE as u64;
+        let mut record = Vec::new();
+        while offset < len {
+            record.clear();
+            // The header, and the tag and length of the hash, tell the length of the record
+            (&mut log).take(HEADER_SIZE as u64 + 2).read_to_end(&mut record)?;
+            // A record whose length can't be read yet ends with the log
+            let end = record_size(&record).map_or(len, |size| offset + size);
+            if end <= len {
+                let rest = end - offset - record.len() as u64;
+                (&mut log).take(rest).read_to_end(&mut record)?;
+            }
+
+            let parsed = parse(&record, offset);
+            let (kind, hash, entry) = match parsed {
+                Some(parsed) => parsed,
+                None if end >= len && !self.is_followed(offset, len)? => break,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "corrupted record at offset {} of {}",
+                            offset,
+                            self.dir.join(LOG_FILE).display()
+                        ),
+                    ))
+                }
+            };
+            match kind {
+                RECORD_PUT => self.index_put(hash, entry),
+                RECORD_DELETE => self.index_delete(hash, entry),
+                _ => {
+                    // Only the latest expiry time matters, compaction folds it in the content
+                    if let Some(indexed) = self.index.get_mut(&hash) {
+                        indexed.expires = entry.expires;
+                    }
+                    self.dead += entry.size;
+                }
+            }
+            offset += entry.size;
+        }
+
+        if offset < len {
+            warn!(
+                "Dropping a torn record of {} bytes at the end of {}",
+                len - offset,
+                self.dir.join(LOG_FILE).display()
+            );
+            self.file.set_len(offset)?;
+            self.file.sync_all()?;
+        }
+        self.size = offset;
+        Ok(())
+    }
+
+    /// Check if a valid record starts anywhere after an offset, up to the end of the log
+    fn is_followed(&self, offset: u64, len: u64) -> io::Result<bool> {
+        let mut rest = Vec::new();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset + 1))?;
+        file.take(len - offset - 1).read_to_end(&mut rest)?;
+        Ok((0..rest.len()).any(|start| parse(&rest[start..], 0).is_some()))
+    }
+
+    /// Index the latest content of a hash
+    fn index_put(&mut self, hash: Hash, entry: Entry) {
+        self.refreshed.remove(&hash);
+        if let Some(old) = self.index.insert(hash, entry) {
+            self.dead += old.size;
+        }
+        self.remove_tombstone(&hash);
+    }
+
+    /// Replace the content of a hash by a tombstone
+    /// The tombstones expiring first make room for it if there are MAX_TOMBSTONES already
+    fn index_delete(&mut self, hash: Hash, entry: Entry) {
+        self.refreshed.remove(&hash);
+        if let Some(old) = self.index.remove(&hash) {
+            self.dead += old.size;
+        }
+        self.remove_tombstone(&hash);
+        if self.tombstones.len() >= MAX_TOMBSTONES {
+            if let Some(&(_, oldest)) = self.expiries.iter().next() {
+                self.remove_tombstone(&oldest);
+            }
+        }
+        self.expiries.insert((entry.expires, hash));
+        self.tombstones.insert(hash, entry);
+    }
+
+    /// Forget the tombstone of a hash, its record being dead
+    fn remove_tombstone(&mut self, hash: &Hash) {
+        if let Some(tombstone) = self.tombstones.remove(hash) {
+            self.expiries.remove(&(tombstone.expires, *hash));
+            self.dead += tombstone.size;
+        }
+    }
+
+    /// Read a record content from the log
+    fn read(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let mut record = vec![0; entry.size as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.read_exact(&mut record)?;
+        match parse(&record, entry.offset) {
+            Some((RECORD_PUT, _, _)) => Ok(record.split_off(record.len() - entry.len as usize)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted record")),
+        }
+    }
+
+    /// Append a record to the log, returning its offset
+    fn append(&mut self, record: &[u8]) -> io::Result<u64> {
+        if let Err(e) = self.file.write_all(record).and_then(|_| self.file.sync_data()) {
+            // Don't leave a partial record behind the ones written next
+            let _ = self.file.set_len(self.size);
+            return Err(e);
+        }
+
+        let offset = self.size;
+        self.size += record.len() as u64;
+        Ok(offset)
+    }
+
+    /// Rewrite the log with only the indexed records and the tombstones
+    pub fn compact(&mut self) -> io::Result<()> {
+        let path = self.dir.join(COMPACT_FILE);
+        let mut compacted = File::create(&path)?;
+        compacted.write_all(&log_header())?;
+        let mut index = HashMap::with_capacity(self.index.len());
+        let mut offset = LOG_HEADER_SIZE as u64;
+        for (hash, entry) in &self.index {
+            let data = self.read(entry)?;
+            let record = encode(RECORD_PUT, hash, entry.expires, entry.ttl, &data);
+            compacted.write_all(&record)?;
+            let size = record.len() as u64;
+            index.insert(*hash, Entry { offset, size, ..*entry });
+            offset += size;
+        }
+        let mut tombstones = HashMap::with_capacity(self.tombstones.len());
+        for (hash, entry) in &self.tombstones {
+            let record = encode(RECORD_DELETE, hash, entry.expires, entry.ttl, &[]);
+            compacted.write_all(&record)?;
+            let size = record.len() as u64;
+            tombstones.insert(*hash, Entry { offset, size, ..*entry });
+            offset += size;
+        }
+        compacted.sync_all()?;
+        drop(compacted);
+
+        // Renaming is atomic: a crash leaves either the old or the new log, and syncing the
+        // directory makes sure the new one stays
+        fs::rename(&path, self.dir.join(LOG_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        debug!("Compacted {}: {} -> {} bytes", self.dir.display(), self.size, offset);
+        self.index = index;
+        self.tombstones = tombstones;
+        // The latest expiry times were just written
+        self.refreshed.clear();
+        self.size = offset;
+        self.dead = 0;
+        Ok(())
+    }
+
+    /// Append the refresh records of the hashes refreshed since the last cleanup, synced at once
+    fn flush(&mut self) -> io::Result<()> {
+        let mut records = Vec::new();
+        for hash in self.refreshed.drain() {
+            if let Some(entry) = self.index.get(&hash) {
+                records.extend(encode(RECORD_REFRESH, &hash, entry.expires, entry.ttl, &[]));
+            }
+        }
+        if !records.is_empty() {
+            self.append(&records)?;
+            self.dead += records.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Could not write the refreshes to {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+impl Storage for DiskStore {
+    fn put(&mut self, hash: &Hash, data: Vec<u8>, ttl: Duration) -> io::Result<()> {
+        if data.len() > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "content too large"));
+        }
+
+        let expires = SystemTime::now() + ttl;
+        let record = encode(RECORD_PUT, hash, expires, ttl, &data);
+        let entry = Entry {
+            offset: self.append(&record)?,
+            size: record.len() as u64,
+            len: data.len() as u32,
+            crc: crc32(&data),
+            expires,
+            ttl,
+        };
+        self.index_put(*hash, entry);
+        Ok(())
+    }
+
+    fn delete(&mut self, hash: &Hash, ttl: Duration) -> io::Result<()> {
+        let expires = SystemTime::now() + ttl;
+        let record = encode(RECORD_DELETE, hash, expires, ttl, &[]);
+        let entry = Entry {
+            offset: self.append(&record)?,
+            size: record.len() as u64,
+            len: 0,
+            crc: 0,
+            expires,
+            ttl,
+        };
+        self.index_delete(*hash, entry);
+        Ok(())
+    }
+
+    fn is_deleted(&self, hash: &Hash) -> bool {
+        self.tombstones
+            .get(hash)
+            .is_some_and(|tombstone| !tombstone.is_stale())
+    }
+
+    fn refresh(&mut self, hash: &Hash) -> io::Result<bool> {
+        match self.index.get_mut(hash) {
+            Some(entry) => entry.expires = SystemTime::now() + entry.ttl,
+            None => return Ok(false),
+        }
+        self.refreshed.insert(*hash);
+        Ok(true)
+    }
+
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+        let entry = self.index.get(hash)?;
+        self.read(entry)
+            .map_err(|e| error!("Could not read {:?} from disk: {}", hash, e))
+            .ok()
+    }
+
+    fn ttl(&self, hash: &Hash) -> Option<Duration> {
+        self.index.get(hash).map(Entry::remaining)
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    fn same_content(&self, hash: &Hash, data: &[u8]) -> bool {
+        self.index
+            .get(hash)
+            .is_some_and(|entry| entry.len as usize == data.len() && entry.crc == crc32(data))
+    }
+
+    fn list(&self) -> Vec<Hash> {
+        self.index.keys().cloned().collect()
+    }
+
+    fn cleanup(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Could not write the refreshes to {}: {}", self.dir.display(), e);
+        }
+
+        let mut dead = 0;
+        self.index.retain(|_, entry| {
+            let stale = entry.is_stale();
+            if stale {
+                dead += entry.size;
+            }
+            !stale
+        });
+        self.dead += dead;
+        // The tombstones expiring first come first
+        let now = SystemTime::now();
+        while let Some(&(expires, hash)) = self.expiries.iter().next() {
+            if expires >= now {
+                break;
+            }
+            self.remove_tombstone(&hash);
+        }
+
+        if self.dead > COMPACT_THRESHOLD && self.dead > self.size / 2 {
+            if let Err(e) = self.compact() {
+                error!("Could not compact {}: {}", self.dir.display(), e);
+            }
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Hash, Vec<u8>)> + 'a> {
+        Box::new(
+            self.index
+                .keys()
+                .filter_map(move |hash| self.get(hash).map(|data| (*hash, data))),
+        )
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            entries: self.index.len(),
+            bytes: self.index.values().map(|entry| entry.len as usize).sum(),
+            ..Stats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, DiskStore, Entry, LOG_FILE, LOG_HEADER_SIZE, LOG_MAGIC};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+    use messages::{Algorithm, Hash};
+    use storage::{Storage, MAX_TOMBSTONES};
+
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    /// An empty directory for a test
+    fn data_dir() -> PathBuf {
+        let n = DIRS.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("simple_dht-{}-{}", process::id(), n));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn log_size(dir: &Path) -> u64 {
+        fs::metadata(dir.join(LOG_FILE)).unwrap().len()
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn persist() {
+        let dir = data_dir();
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            store.put(&Hash::from(1), vec![1, 2, 3], TTL).unwrap();
+            store.put(&Hash::from(2), vec![4], TTL).unwrap();
+            store.put(&Hash::from(1), vec![5, 6], TTL).unwrap();
+            assert_eq!(store.get(&Hash::from(1)), Some(vec![5, 6]));
+        }
+
+        // The latest content of each hash is found again after a restart
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.get(&Hash::from(1)), Some(vec![5, 6]));
+        assert_eq!(store.get(&Hash::from(2)), Some(vec![4]));
+        assert_eq!(store.get(&Hash::from(3)), None);
+        assert_eq!(store.iter().count(), 2);
+        assert!(store.ttl(&Hash::from(1)).unwrap() > Duration::from_secs(50));
+        // Contents are compared without being read again
+        assert!(store.same_content(&Hash::from(1), &[5, 6]));
+        assert!(!store.same_content(&Hash::from(1), &[1, 2]));
+        assert!(!store.same_content(&Hash::from(3), &[]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recover() {
+        let dir = data_dir();
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            store.put(&Hash::from(1), vec![1, 2, 3], TTL).unwrap();
+            store.put(&Hash::from(2), vec![4, 5, 6], TTL).unwrap();
+        }
+        let valid = log_size(&dir);
+
+        // A crash in the middle of a write leaves a truncated record…
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(&[0x12, 0x34, 0x56]).unwrap();
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(log_size(&dir), valid);
+        assert_eq!(store.get(&Hash::from(2)), Some(vec![4, 5, 6]));
+        drop(store);
+
+        // …and a corrupted one is detected by its checksum
+        let mut bytes = fs::read(dir.join(LOG_FILE)).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(dir.join(LOG_FILE), &bytes).unwrap();
+        let mut store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.get(&Hash::from(1)), Some(vec![1, 2, 3]));
+        assert_eq!(store.get(&Hash::from(2)), None);
+
+        // New records are appended after the valid ones
+        store.put(&Hash::from(3), vec![7], TTL).unwrap();
+        drop(store);
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.get(&Hash::from(3)), Some(vec![7]));
+        drop(store);
+
+        // A corrupted record followed by others isn't a torn write: the log is left untouched
+        let mut bytes = fs::read(dir.join(LOG_FILE)).unwrap();
+        bytes[10] ^= 0xff;
+        fs::write(dir.join(LOG_FILE), &bytes).unwrap();
+        assert!(DiskStore::open(&dir).is_err());
+        assert_eq!(fs::read(dir.join(LOG_FILE)).unwrap(), bytes);
+
+        // even if its corrupted length makes it look like it reaches the end of the log
+        bytes[10] ^= 0xff;
+        bytes[LOG_HEADER_SIZE + 17] = 0x7f;
+        fs::write(dir.join(LOG_FILE), &bytes).unwrap();
+        assert!(DiskStore::open(&dir).is_err());
+        assert_eq!(fs::read(dir.join(LOG_FILE)).unwrap(), bytes);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refresh() {
+        let dir = data_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        store.put(&Hash::from(1), vec![1, 2, 3], Duration::from_secs(0)).unwrap();
+        store.put(&Hash::from(2), vec![4], Duration::from_secs(0)).unwrap();
+        assert!(!store.refresh(&Hash::from(3)).unwrap());
+
+        // Refreshing a hash keeps it for another time to live, even after a restart
+        // (the first one is given a longer time to live, so the refresh can be told apart)
+        store.index.get_mut(&Hash::from(1)).unwrap().ttl = TTL;
+        let size = log_size(&dir);
+        assert!(store.refresh(&Hash::from(1)).unwrap());
+        // Refreshes are only written on the next cleanup, or when the store is closed
+        assert_eq!(log_size(&dir), size);
+        drop(store);
+        let mut store = DiskStore::open(&dir).unwrap();
+        assert!(store.ttl(&Hash::from(1)).unwrap() > Duration::from_secs(50));
+        assert_eq!(store.ttl(&Hash::from(2)), Some(Duration::from_secs(0)));
+
+        // and compaction keeps the latest expiry time
+        store.compact().unwrap();
+        assert!(store.ttl(&Hash::from(1)).unwrap() > Duration::from_secs(50));
+        assert_eq!(store.get(&Hash::from(1)), Some(vec![1, 2, 3]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compact() {
+        let dir = data_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        for i in 0..10 {
+            store.put(&Hash::from(1), vec![i; 100], TTL).unwrap();
+        }
+        store.put(&Hash::from(2), vec![42; 10], TTL).unwrap();
+        let before = log_size(&dir);
+
+        // Only the latest records are kept
+        store.compact().unwrap();
+        assert!(log_size(&dir) < before / 4);
+        assert_eq!(store.get(&Hash::from(1)), Some(vec![9; 100]));
+        store.put(&Hash::from(3), vec![3], TTL).unwrap();
+        drop(store);
+
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.get(&Hash::from(1)), Some(vec![9; 100]));
+        assert_eq!(store.get(&Hash::from(2)), Some(vec![42; 10]));
+        assert_eq!(store.get(&Hash::from(3)), Some(vec![3]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delete() {
+        let dir = data_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        store.put(&Hash::from(1), vec![1, 2, 3], TTL).unwrap();
+        store.put(&Hash::from(2), vec![4], TTL).unwrap();
+        store.delete(&Hash::from(1), TTL).unwrap();
+        store.delete(&Hash::from(2), TTL).unwrap();
+        store.put(&Hash::from(2), vec![5], TTL).unwrap();
+        store.delete(&Hash::from(3), Duration::from_secs(0)).unwrap();
+
+        // Tombstones survive restarts and compactions, until they expire
+        for _ in 0..2 {
+            drop(store);
+            store = DiskStore::open(&dir).unwrap();
+            assert_eq!(store.get(&Hash::from(1)), None);
+            assert!(store.is_deleted(&Hash::from(1)));
+            assert_eq!(store.get(&Hash::from(2)), Some(vec![5]));
+            assert!(!store.is_deleted(&Hash::from(2)));
+            store.compact().unwrap();
+        }
+        store.cleanup();
+        assert!(!store.is_deleted(&Hash::from(3)));
+        assert_eq!(store.tombstones.len(), 1);
+
+        // The tombstones expiring first make room for new ones
+        let expires = SystemTime::now() + TTL * 2;
+        for i in 0..MAX_TOMBSTONES as u64 {
+            let entry = Entry {
+                offset: 0,
+                size: 1,
+                len: 0,
+                crc: 0,
+                expires,
+                ttl: TTL,
+            };
+            store.index_delete(Hash::from(i + 100), entry);
+        }
+        assert_eq!(store.tombstones.len(), MAX_TOMBSTONES);
+        assert!(!store.is_deleted(&Hash::from(1)));
+        assert!(store.is_deleted(&Hash::from(100)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn versions() {
+        let dir = data_dir();
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            let content = Hash::of_content(Algorithm::Blake3, &[1, 2, 3]);
+            store.put(&content, vec![1, 2, 3], TTL).unwrap();
+        }
+        let log = fs::read(dir.join(LOG_FILE)).unwrap();
+        assert!(log.starts_with(LOG_MAGIC));
+
+        // Logs of unknown versions, or without a header, are refused and left untouched
+        let mut newer = log.clone();
+        newer[LOG_HEADER_SIZE - 1] = 42;
+        let headless = log[LOG_HEADER_SIZE..].to_vec();
+        let garbage = vec![0xff; 100];
+        for log in &[newer, headless, garbage] {
+            fs::write(dir.join(LOG_FILE), log).unwrap();
+            assert!(DiskStore::open(&dir).is_err());
+            assert_eq!(&fs::read(dir.join(LOG_FILE)).unwrap(), log);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,828 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::{self, FromStr};
+use std::time::Duration;
+use bytes::BytesMut;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{future, stream, Future, Sink, Stream};
+use httparse;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{Decoder, Encoder, Framed};
+use tokio_timer::{TimeoutError, Timer};
+
+use chunks::{self, ChunkError, Manifest};
+use messages::{ErrorCode, Hash, Message, Payload};
+use state::{Origin, State};
+use websocket::{self, WebSocketCodec};
+
+/// How many connections are served at once
+/// The others wait to be accepted
+const MAX_CONNECTIONS: usize = 64;
+
+/// How many WebSocket subscribers are served at once
+/// Each one is a listener every broadcast goes through
+const MAX_SUBSCRIBERS: usize = 64;
+
+/// How long a client may take to send its request, in seconds
+const READ_TIMEOUT: u64 = 10;
+
+/// How many headers a request may have
+const MAX_HEADERS: usize = 32;
+
+/// How long the head of a request may be, in bytes
+const MAX_HEAD: usize = 8 * 1024;
+
+/// How long the body of a request may be, in bytes
+/// Bodies are buffered whole, for each of the MAX_CONNECTIONS
+const MAX_BODY: usize = 4 * 1024 * 1024;
+
+/// An HTTP request, with its whole body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// The path, along with the query string
+    pub path: String,
+    pub body: Vec<u8>,
+    /// The Sec-WebSocket-Key of a client asking for a WebSocket upgrade
+    pub websocket: Option<String>,
+}
+
+impl Request {
+    /// The path without the query string, and the query string
+    fn split_query(&self) -> (&str, &str) {
+        self.path.split_once('?').unwrap_or((&self.path, ""))
+    }
+}
+
+/// An HTTP response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+    /// The Sec-WebSocket-Accept of a WebSocket upgrade
+    pub accept: Option<String>,
+}
+
+impl Response {
+    /// A plain text response, mostly to tell what went wrong
+    fn text<S: Into<String>>(status: u16, text: S) -> Self {
+        let mut body = text.into().into_bytes();
+        body.push(b'\n');
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body,
+            accept: None,
+        }
+    }
+
+    /// A JSON response
+    fn json(json: String) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/json",
+            body: json.into_bytes(),
+            accept: None,
+        }
+    }
+
+    /// The value of a hash
+    fn value(Payload(body): Payload) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+            accept: None,
+        }
+    }
+
+    /// Accept a WebSocket upgrade
+    fn upgrade(key: &str) -> Self {
+        Response {
+            status: 101,
+            content_type: "",
+            body: Vec::new(),
+            accept: Some(websocket::accept_key(key)),
+        }
+    }
+
+    /// The response to an ERROR message
+    fn error(code: ErrorCode, reason: String) -> Self {
+        match code {
+            ErrorCode::InvalidMessage | ErrorCode::InvalidRequest => Response::text(400, reason),
+            ErrorCode::Internal | ErrorCode::Other(_) => Response::text(500, reason),
+        }
+    }
+
+    /// The response to a write: OK if enough replicas hold it, a timeout otherwise
+    fn quorum(hash: Hash, replicas: u8, required: u8) -> Self {
+        if replicas < required {
+            let reason = format!("quorum not reached: {}/{} replicas", replicas, required);
+            return Response::text(504, reason);
+        }
+        Response::json(format!(
+            "{{\"hash\":\"{:?}\",\"replicas\":{},\"required\":{}}}",
+            hash, replicas, required
+        ))
+    }
+}
+
+/// The reason phrase of a status code
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Why a request could not be read
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    /// The request is not valid HTTP
+    Malformed,
+    /// The body length is not given upfront
+    LengthRequired,
+    /// The head or the body of the request is too long
+    TooLarge,
+    /// The client took too long to send its request
+    TimedOut,
+}
+
+impl HttpError {
+    /// What to tell the client, if it can still be told anything
+    fn response(&self) -> Option<Response> {
+        match *self {
+            HttpError::Io(_) => None,
+            HttpError::Malformed => Some(Response::text(400, "malformed request")),
+            HttpError::LengthRequired => Some(Response::text(411, "missing content length")),
+            HttpError::TooLarge => Some(Response::text(413, "request too large")),
+            HttpError::TimedOut => Some(Response::text(408, "request took too long")),
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(src: io::Error) -> HttpError {
+        HttpError::Io(src)
+    }
+}
+
+impl From<httparse::Error> for HttpError {
+    fn from(_: httparse::Error) -> HttpError {
+        HttpError::Malformed
+    }
+}
+
+impl<T> From<TimeoutError<T>> for HttpError {
+    fn from(src: TimeoutError<T>) -> HttpError {
+        match src {
+            TimeoutError::TimedOut(_) => HttpError::TimedOut,
+            TimeoutError::Timer(_, e) => HttpError::Io(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// Reads HTTP/1.1 requests, and writes the responses to them
+/// The connection is closed after each response
+pub struct HttpCodec;
+
+impl Decoder for HttpCodec {
+    type Item = Request;
+    type Error = HttpError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>, HttpError> {
+        let (head_len, method, path, body_len, websocket) = {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut req = httparse::Request::new(&mut headers);
+            let head_len = match req.parse(buf)? {
+                httparse::Status::Complete(len) => len,
+                httparse::Status::Partial if buf.len() > MAX_HEAD => {
+                    return Err(HttpError::TooLarge)
+                }
+                httparse::Status::Partial => return Ok(None),
+            };
+
+            let (mut body_len, mut upgrade, mut key) = (None, false, None);
+            for header in req.headers.iter() {
+                if header.name.eq_ignore_ascii_case("content-length") {
+                    let len = str::from_utf8(header.value)
+                        .ok()
+                        .and_then(|len| len.trim().parse().ok())
+                        .ok_or(HttpError::Malformed)?;
+                    // Lengths that disagree leave the end of the body ambiguous
+                    if body_len.is_some_and(|body_len| body_len != len) {
+                        return Err(HttpError::Malformed);
+                    }
+                    body_len = Some(len);
+                } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                    return Err(HttpError::LengthRequired);
+                } else if header.name.eq_ignore_ascii_case("upgrade") {
+                    upgrade = header.value.eq_ignore_ascii_case(b"websocket");
+                } else if header.name.eq_ignore_ascii_case("sec-websocket-key") {
+                    let value = str::from_utf8(header.value).map_err(|_| HttpError::Malformed)?;
+                    key = Some(value.trim().to_owned());
+                }
+            }
+            let websocket = if upgrade { key } else { None };
+            let method = req.method.unwrap_or_default().to_owned();
+            let path = req.path.unwrap_or_default().to_owned();
+            (head_len, method, path, body_len.unwrap_or(0), websocket)
+        };
+
+        if body_len > MAX_BODY {
+            return Err(HttpError::TooLarge);
+        }
+        if buf.len() < head_len + body_len {
+            buf.reserve(head_len + body_len - buf.len());
+            return Ok(None);
+        }
+
+        buf.split_to(head_len);
+        let body = buf.split_to(body_len).to_vec();
+        Ok(Some(Request {
+            method,
+            path,
+            body,
+            websocket,
+        }))
+    }
+}
+
+impl Encoder for HttpCodec {
+    type Item = Response;
+    type Error = HttpError;
+
+    fn encode(&mut self, resp: Response, buf: &mut BytesMut) -> Result<(), HttpError> {
+        if let Some(accept) = resp.accept {
+            let head = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            );
+            buf.extend_from_slice(head.as_bytes());
+            return Ok(());
+        }
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            resp.status,
+            reason(resp.status),
+            resp.content_type,
+            resp.body.len()
+        );
+        buf.extend_from_slice(head.as_bytes());
+        buf.extend_from_slice(&resp.body);
+        Ok(())
+    }
+}
+
+/// Expose the state of this node over HTTP
+pub fn listen<'a>(
+    state: &'a State,
+    addr: &SocketAddr,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    let listener = TcpListener::bind(addr, handle).expect("Could not bind socket");
+    println!("Listening on http://{}", listener.local_addr().unwrap());
+    serve(state, listener, handle)
+}
+
+/// The hashes a subscriber watches, or None for all of them
+type Watched = Option<HashSet<Hash>>;
+
+/// The halves of an HTTP connection
+type Connection = (
+    SplitSink<Framed<TcpStream, HttpCodec>>,
+    SplitStream<Framed<TcpStream, HttpCodec>>,
+);
+
+/// Answer the HTTP requests coming on an already bound listener
+/// WebSocket subscriptions are spawned on the event loop, and don't hold a connection slot
+pub fn serve<'a>(
+    state: &'a State,
+    listener: TcpListener,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    let timer = Timer::default();
+    let handle = handle.clone();
+    let subscribers = Rc::new(Cell::new(0));
+    let connections = listener
+        .incoming()
+        .map_err(|e| error!("Error accepting connection: {}", e))
+        .map(move |(stream, addr)| {
+            let (sink, requests) = stream.framed(HttpCodec).split();
+            let request = requests.into_future().map_err(|(e, _)| e);
+            let request = timer.timeout(request, Duration::from_secs(READ_TIMEOUT));
+            let handle = handle.clone();
+            let subscribers = Rc::clone(&subscribers);
+            let served = request.then(move |request| -> Box<dyn Future<Item = (), Error = ()>> {
+                let response = match request {
+                    Ok((Some(req), requests)) => {
+                        debug!("HTTP request from {}: {} {}", addr, req.method, req.path);
+                        if req.split_query().0 == "/subscribe" {
+                            return subscribe(state, &req, (sink, requests), &handle, &subscribers);
+                        }
+                        respond(state, addr, req)
+                    }
+                    // The client left without asking anything
+                    Ok((None, _)) => return Box::new(future::ok(())),
+                    Err(e) => {
+                        debug!("Invalid HTTP request from {}: {:?}", addr, e);
+                        match e.response() {
+                            Some(response) => Box::new(future::ok(response)),
+                            None => return Box::new(future::ok(())),
+                        }
+                    }
+                };
+                let sent = response.and_then(move |response| sink.send(response).map_err(|_| ()));
+                Box::new(sent.map(|_| ()))
+            });
+            // A connection failing must not stop the others
+            served.then(|_| Ok(()))
+        })
+        .buffer_unordered(MAX_CONNECTIONS)
+        .for_each(|()| Ok(()));
+    Box::new(connections)
+}
+
+/// Check a subscription request, and tell which hashes it watches
+/// Every hash is watched when none is given in the query
+fn subscription(req: &Request, subscribers: usize) -> Result<(String, Watched), Response> {
+    if req.method != "GET" {
+        return Err(Response::text(405, "expected GET"));
+    }
+    let key = match req.websocket {
+        Some(ref key) => key.clone(),
+        None => return Err(Response::text(400, "expected a WebSocket upgrade")),
+    };
+    if subscribers >= MAX_SUBSCRIBERS {
+        return Err(Response::text(503, "too many subscribers"));
+    }
+
+    let mut watched = HashSet::new();
+    for param in req.split_query().1.split('&') {
+        if let Some(hash) = param.strip_prefix("hash=") {
+            let hash = Hash::from_str(hash).map_err(|e| Response::text(400, e.to_string()))?;
+            watched.insert(hash);
+        }
+    }
+    let watched = if watched.is_empty() { None } else { Some(watched) };
+    Ok((key, watched))
+}
+
+/// Turn a connection into a WebSocket subscription, or tell the client why it can't be
+fn subscribe(
+    state: &State,
+    req: &Request,
+    connection: Connection,
+    handle: &Handle,
+    subscribers: &Rc<Cell<usize>>,
+) -> Box<dyn Future<Item = (), Error = ()>> {
+    let (key, watched) = match subscription(req, subscribers.get()) {
+        Ok(subscription) => subscription,
+        Err(response) => return Box::new(connection.0.send(response).then(|_| Ok(()))),
+    };
+
+    subscribers.set(subscribers.get() + 1);
+    let subscribers = Rc::clone(subscribers);
+    let leave = move || subscribers.set(subscribers.get() - 1);
+    let handle = handle.clone();
+    Box::new(upgrade(state, &key, watched, connection).then(move |session| {
+        match session {
+            Ok(session) => handle.spawn(session.then(move |_| {
+                leave();
+                Ok(())
+            })),
+            Err(()) => leave(),
+        }
+        Ok(())
+    }))
+}
+
+/// Accept a WebSocket upgrade, and turn the connection into a subscription
+/// Changes are listened to before the upgrade is sent, so that no event is missed
+fn upgrade(
+    state: &State,
+    key: &str,
+    watched: Watched,
+    (sink, requests): Connection,
+) -> Box<dyn Future<Item = Box<dyn Future<Item = (), Error = ()>>, Error = ()>> {
+    let changes = state.subscribe_changes();
+    let upgraded = sink.send(Response::upgrade(key)).map_err(|_| ());
+    Box::new(upgraded.and_then(move |sink| {
+        let connection = requests.reunite(sink).map_err(|_| error!("Could not upgrade"))?;
+        let socket = Framed::from_parts(connection.into_parts(), WebSocketCodec);
+        Ok(websocket::session(changes, watched, socket))
+    }))
+}
+
+/// Answer a request
+fn respond<'a>(
+    state: &'a State,
+    addr: SocketAddr,
+    req: Request,
+) -> Box<dyn Future<Item = Response, Error = ()> + 'a> {
+    let (path, query) = req.split_query();
+    let origin = Origin::Client(addr);
+
+    if let Some(hash) = path.strip_prefix("/hash/") {
+        let hash = match Hash::from_str(hash) {
+            Ok(hash) => hash,
+            Err(e) => return Box::new(future::ok(Response::text(400, e.to_string()))),
+        };
+        return match req.method.as_str() {
+            "GET" => get(state, origin, hash),
+            "PUT" => {
+                // The hash is kept for as long as the client asks, and republished otherwise
+                let ttl = query
+                    .split('&')
+                    .filter_map(|param| param.strip_prefix("ttl="))
+                    .map(u32::from_str)
+                    .next();
+                match ttl {
+                    Some(Err(e)) => Box::new(future::ok(Response::text(400, e.to_string()))),
+                    Some(Ok(ttl)) => put(state, origin, hash, req.body, Some(ttl)),
+                    None => put(state, origin, hash, req.body, None),
+                }
+            }
+            "DELETE" => {
+                let responses = exchange(state, Message::Delete(hash), origin);
+                Box::new(responses.map(move |responses| {
+                    last_response(responses).unwrap_or_else(|| {
+                        Response::text(500, format!("could not delete {:?}", hash))
+                    })
+                }))
+            }
+            _ => Box::new(future::ok(Response::text(405, "expected GET, PUT or DELETE"))),
+        };
+    }
+
+    let response = match (req.method.as_str(), path) {
+        ("GET", "/peers") => {
+            let peers: Vec<_> = state
+                .peers()
+                .into_iter()
+                .map(|(id, addr)| format!("{{\"id\":\"{:?}\",\"address\":\"{}\"}}", id, addr))
+                .collect();
+            Response::json(format!("[{}]", peers.join(",")))
+        }
+        ("GET", "/stats") => {
+            let stats = state.stats();
+            Response::json(format!(
+                "{{\"id\":\"{:?}\",\"entries\":{},\"bytes\":{},\"evictions\":{},\
+                 \"evicted_bytes\":{}}}",
+                state.id(),
+                stats.entries,
+                stats.bytes,
+                stats.evictions,
+                stats.evicted_bytes
+            ))
+        }
+        (_, "/peers") | (_, "/stats") => Response::text(405, "expected GET"),
+        _ => Response::text(404, format!("no such resource {}", path)),
+    };
+    Box::new(future::ok(response))
+}
+
+/// Process a message from a client, and gather the responses
+fn exchange<'a>(
+    state: &'a State,
+    msg: Message,
+    origin: Origin,
+) -> Box<dyn Future<Item = Vec<Message>, Error = ()> + 'a> {
+    Box::new(state.process(msg, None, origin).map(|(_, msg)| msg).collect())
+}
+
+/// The response to a write, from the messages it got
+fn last_response(responses: Vec<Message>) -> Option<Response> {
+    responses.into_iter().rev().find_map(|resp| match resp {
+        Message::Error(code, reason) => Some(Response::error(code, reason)),
+        Message::Quorum(hash, replicas, required) => {
+            Some(Response::quorum(hash, replicas, required))
+        }
+        _ => None,
+    })
+}
+
+/// The value found by a lookup, or the response telling why there is none
+fn found(hash: Hash, responses: Vec<Message>) -> Result<Payload, Response> {
+    let mut payload = None;
+    for resp in responses {
+        match resp {
+            Message::Error(code, reason) => return Err(Response::error(code, reason)),
+            Message::NotFound(_) => {
+                return Err(Response::text(404, format!("hash {:?} not found", hash)))
+            }
+            Message::Put(_, data, _) => payload = Some(data),
+            // Not enough replicas answered before the lookup timed out
+            Message::Quorum(_, replicas, required) if replicas < required => {
+                let reason = format!("quorum not reached: {}/{} replicas", replicas, required);
+                return Err(Response::text(504, reason));
+            }
+            _ => (),
+        }
+    }
+    payload.ok_or_else(|| Response::text(504, format!("lookup of {:?} cancelled", hash)))
+}
+
+/// Get the value of a hash, fetching its chunks if it was split
+fn get(
+    state: &State,
+    origin: Origin,
+    hash: Hash,
+) -> Box<dyn Future<Item = Response, Error = ()> + '_> {
+    Box::new(exchange(state, Message::Get(hash), origin).and_then(move |responses| {
+        match found(hash, responses) {
+            Ok(payload) => match Manifest::from_payload(&payload) {
+                Some(manifest) => future::Either::A(fetch(state, origin, manifest)),
+                None => future::Either::B(future::ok(Response::value(payload))),
+            },
+            Err(response) => future::Either::B(future::ok(response)),
+        }
+    }))
+}
+
+/// Fetch the chunks listed in a manifest and put the value back together
+fn fetch(
+    state: &State,
+    origin: Origin,
+    manifest: Manifest,
+) -> Box<dyn Future<Item = Response, Error = ()> + '_> {
+    let chunks = stream::iter_ok(manifest.chunks.clone()).and_then(move |hash: Hash| {
+        exchange(state, Message::Get(hash), origin).map(move |responses| found(hash, responses))
+    });
+    Box::new(chunks.collect().map(move |chunks| {
+        let chunks = chunks.into_iter().map(|chunk| chunk.map(|Payload(data)| data));
+        // A chunk that can't be found means the value can't be read
+        let chunks = match chunks.collect::<Result<Vec<_>, _>>() {
+            Ok(chunks) => chunks,
+            Err(_) => return Response::text(502, ChunkError::MissingChunks.to_string()),
+        };
+        match manifest.assemble(chunks) {
+            Ok(value) => Response::value(Payload(value)),
+            Err(e) => Response::text(502, e.to_string()),
+        }
+    }))
+}
+
+/// Put a value under a hash, in chunks if it is too large
+fn put(
+    state: &State,
+    origin: Origin,
+    hash: Hash,
+    value: Vec<u8>,
+    ttl: Option<u32>,
+) -> Box<dyn Future<Item = Response, Error = ()> + '_> {
+    let messages = match chunks::put_messages(hash, value, ttl) {
+        Ok(messages) => messages,
+        Err(e) => return Box::new(future::ok(Response::text(413, e.to_string()))),
+    };
+
+    // The chunks come first, and the manifest last: stop at the first one that isn't stored
+    let puts = stream::iter_ok(messages).and_then(move |msg| {
+        exchange(state, msg, origin).map(|responses| {
+            last_response(responses).unwrap_or_else(|| Response::text(500, "no answer"))
+        })
+    });
+    let puts = puts.map_err(|()| Response::text(500, "could not store the value"));
+    let stored = puts.fold(None, |_, response: Response| {
+        if response.status == 200 {
+            Ok(Some(response))
+        } else {
+            Err(response)
+        }
+    });
+    Box::new(stored.then(|stored| match stored {
+        Ok(Some(response)) | Err(response) => Ok(response),
+        Ok(None) => Ok(Response::text(500, "nothing to store")),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{found, serve, HttpCodec, HttpError, Request, Response, MAX_BODY};
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+    use bytes::BytesMut;
+    use futures::Future;
+    use futures::future::Either;
+    use futures::sync::oneshot;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    use messages::{Hash, Message, Payload};
+    use state::State;
+
+    #[test]
+    fn decode_request() {
+        let mut buf = BytesMut::from(&b"PUT /hash/42 HTTP/1.1\r\nContent-Length: 5\r\n"[..]);
+        assert!(HttpCodec.decode(&mut buf).unwrap().is_none());
+
+        // The body is only read once all of it came
+        buf.extend_from_slice(b"\r\nhel");
+        assert!(HttpCodec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"lo");
+        assert_eq!(
+            HttpCodec.decode(&mut buf).unwrap(),
+            Some(Request {
+                method: "PUT".to_owned(),
+                path: "/hash/42".to_owned(),
+                body: b"hello".to_vec(),
+                websocket: None,
+            })
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut buf = BytesMut::from(&b"GET /\x01 HTTP/1.1\r\n\r\n"[..]);
+        assert!(matches!(HttpCodec.decode(&mut buf), Err(HttpError::Malformed)));
+
+        let mut buf = BytesMut::from(&b"PUT / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n"[..]);
+        assert!(matches!(HttpCodec.decode(&mut buf), Err(HttpError::TooLarge)));
+        let head = format!("PUT / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        let mut buf = BytesMut::from(head.as_bytes());
+        assert!(matches!(HttpCodec.decode(&mut buf), Err(HttpError::TooLarge)));
+
+        let head = "PUT / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab";
+        let mut buf = BytesMut::from(head.as_bytes());
+        assert!(matches!(HttpCodec.decode(&mut buf), Err(HttpError::Malformed)));
+
+        let mut buf = BytesMut::from(&b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"[..]);
+        assert!(matches!(HttpCodec.decode(&mut buf), Err(HttpError::LengthRequired)));
+
+        // The head never ends
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        buf.extend_from_slice(&[b'a'; 9000]);
+        assert!(matches!(HttpCodec.decode(&mut buf), Err(HttpError::TooLarge)));
+    }
+
+    #[test]
+    fn encode_response() {
+        let mut buf = BytesMut::new();
+        HttpCodec.encode(Response::text(404, "nope"), &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            &b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain; charset=utf-8\r\n\
+               Content-Length: 5\r\nConnection: close\r\n\r\nnope\n"[..]
+        );
+    }
+
+    /// Send a request on a new connection, and read the status and body of the response
+    fn send(server: net::SocketAddr, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = net::TcpStream::connect(server).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let head = format!(
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            method,
+            path,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        // The server closes the connection after the response
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[9..12]).parse().unwrap();
+        (status, response[end + 4..].to_vec())
+    }
+
+    /// Run a gateway, and a blocking client against it in another thread
+    /// Fails if the gateway stops before the client is done
+    fn with_gateway<F>(client: F)
+    where
+        F: FnOnce(net::SocketAddr) + Send + 'static,
+    {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let state = State::default();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(&state, listener, &handle);
+
+        let (done, finished) = oneshot::channel();
+        thread::spawn(move || {
+            client(addr);
+            done.send(()).unwrap();
+        });
+
+        let result = core.run(server.select2(finished));
+        match result {
+            Ok(Either::B((_, _))) => (),
+            _ => panic!("The server or the client stopped"),
+        }
+    }
+
+    #[test]
+    fn gateway() {
+        with_gateway(|addr| {
+            let (status, body) = send(addr, "PUT", "/hash/42", b"hello");
+            assert_eq!(status, 200);
+            assert!(String::from_utf8(body).unwrap().contains("\"replicas\":1"));
+            assert_eq!(send(addr, "GET", "/hash/42", b""), (200, b"hello".to_vec()));
+
+            let (status, body) = send(addr, "GET", "/stats", b"");
+            assert_eq!(status, 200);
+            assert!(String::from_utf8(body).unwrap().contains("\"entries\":1"));
+            assert_eq!(send(addr, "GET", "/peers", b""), (200, b"[]".to_vec()));
+
+            assert_eq!(send(addr, "DELETE", "/hash/42", b"").0, 200);
+            assert_eq!(send(addr, "GET", "/hash/42", b"").0, 404);
+
+            assert_eq!(send(addr, "GET", "/hash/nope", b"").0, 400);
+            assert_eq!(send(addr, "POST", "/hash/42", b"").0, 405);
+            assert_eq!(send(addr, "GET", "/nope", b"").0, 404);
+        });
+    }
+
+    #[test]
+    fn lookups() {
+        let hash = Hash::from(42);
+        let value = Payload(b"hello".to_vec());
+        let responses = vec![Message::Put(hash, value.clone(), None), Message::Quorum(hash, 1, 1)];
+        assert_eq!(found(hash, responses), Ok(value));
+
+        // A lookup that timed out isn't a missing hash
+        let status = |responses| found(hash, responses).unwrap_err().status;
+        assert_eq!(status(vec![Message::Quorum(hash, 0, 1)]), 504);
+        assert_eq!(status(vec![Message::NotFound(hash)]), 404);
+    }
+
+    /// Read a frame sent by the server, which is never masked
+    fn read_frame(stream: &mut net::TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        let mut payload = vec![0; usize::from(head[1])];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+
+    #[test]
+    fn subscriptions() {
+        with_gateway(|addr| {
+            // Plain requests can't subscribe
+            assert_eq!(send(addr, "GET", "/subscribe", b"").0, 400);
+
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+                .write_all(
+                    b"GET /subscribe?hash=42 HTTP/1.1\r\nUpgrade: websocket\r\n\
+                      Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                      Sec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+            assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+            // Only the watched hashes are told about
+            assert_eq!(send(addr, "PUT", "/hash/41", b"other").0, 200);
+            assert_eq!(send(addr, "PUT", "/hash/42", b"hello").0, 200);
+            let put = b"{\"event\":\"put\",\"hash\":\"0000000000000042\"}".to_vec();
+            assert_eq!(read_frame(&mut stream), (0x81, put));
+            assert_eq!(send(addr, "DELETE", "/hash/42", b"").0, 200);
+            let delete = b"{\"event\":\"delete\",\"hash\":\"0000000000000042\"}".to_vec();
+            assert_eq!(read_frame(&mut stream), (0x81, delete));
+
+            // Pings are answered, and closing is acknowledged
+            stream.write_all(&[0x89, 0x82, 0, 0, 0, 0, 1, 2]).unwrap();
+            assert_eq!(read_frame(&mut stream), (0x8a, vec![1, 2]));
+            stream.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+            assert_eq!(read_frame(&mut stream), (0x88, vec![]));
+        });
+    }
+}
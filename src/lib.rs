@@ -1,16 +1,32 @@
+extern crate base64;
+extern crate blake3;
+extern crate bytes;
 extern crate futures;
+extern crate httparse;
 #[macro_use]
 extern crate log;
+extern crate net2;
 extern crate rustyline;
+extern crate sha1;
+extern crate sha2;
 extern crate shlex;
 extern crate structopt;
 #[macro_use]
 extern crate structopt_derive;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_uds;
 extern crate tokio_timer;
 
 pub mod messages;
+pub mod chunks;
+pub mod storage;
+pub mod disk;
 pub mod state;
+pub mod routing;
+pub mod ring;
 pub mod server;
+pub mod http;
+pub mod websocket;
 pub mod client;
 pub mod cli;
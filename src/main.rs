@@ -8,14 +8,17 @@ extern crate tokio_core;
 
 extern crate simple_dht;
 
+use std::process;
 use futures::stream::futures_unordered;
 use futures::Stream;
 use tokio_core::reactor::Core;
 use structopt::StructOpt;
 
-use simple_dht::server;
-use simple_dht::state::State;
-use simple_dht::client;
+use simple_dht::disk::DiskStore;
+use simple_dht::{http, server};
+use simple_dht::state::{Replication, State};
+use simple_dht::storage::{HashStore, Limits, Storage};
+use simple_dht::client::{self, Endpoint};
 use simple_dht::cli;
 
 fn main() {
@@ -31,14 +34,70 @@ fn main() {
     let handle = core.handle();
 
     match args {
-        cli::CLI::Server { bind } => {
+        cli::CLI::Server {
+            bind,
+            reply_errors,
+            mode,
+            replicas,
+            read_quorum,
+            write_quorum,
+            data_dir,
+            max_bytes,
+            max_entries,
+            eviction,
+            unix,
+            http,
+        } => {
+            let replication = Replication {
+                replicas,
+                read_quorum,
+                write_quorum,
+            };
             // Create state…
-            let state = State::default();
+            if data_dir.is_some() && (max_bytes.is_some() || max_entries.is_some()) {
+                warn!("Store limits only apply to the in-memory store, ignoring them");
+            }
+            let storage: Box<dyn Storage> = match data_dir {
+                Some(dir) => match DiskStore::open(&dir) {
+                    Ok(store) => Box::new(store),
+                    Err(e) => {
+                        eprintln!("Could not open {}: {}", dir.display(), e);
+                        process::exit(1);
+                    }
+                },
+                None => {
+                    let limits = Limits {
+                        max_bytes,
+                        max_entries,
+                        eviction,
+                    };
+                    Box::new(HashStore::with_limits(limits))
+                }
+            };
+            let state = match State::with_storage(replication, storage) {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("Invalid replication settings: {}", e);
+                    process::exit(1);
+                }
+            };
+            let config = server::Config {
+                reply_errors,
+                partitioning: mode,
+            };
             // …listen on addresses…
-            let mut futures: Vec<_> = bind.0
-                .into_iter()
-                .map(|addr| server::listen(&state, &addr, &handle))
-                .collect();
+            let mut futures = Vec::new();
+            for cli::Addrs(protocol, addrs) in bind {
+                for addr in addrs {
+                    futures.push(server::listen(&state, protocol, &addr, &config, &handle));
+                }
+            }
+            if let Some(path) = unix {
+                futures.push(server::listen_unix(&state, &path, &config, &handle));
+            }
+            if let Some(addr) = http {
+                futures.push(http::listen(&state, &addr, &handle));
+            }
 
             // …show interactive prompt…
             futures.push(cli::prompt(&state, &handle));
@@ -50,12 +109,39 @@ fn main() {
             debug!("Starting event loop");
             core.run(stream.collect()).unwrap();
         }
-        cli::CLI::Client { connect, command } => {
-            // Get Message structure from command line arguments
-            let msg = command.to_message();
+        cli::CLI::Client {
+            connect,
+            unix,
+            command,
+        } => {
+            let endpoint = match (connect, unix) {
+                (Some(cli::Addrs(protocol, addrs)), None) => Endpoint::new(protocol, addrs[0]),
+                (None, Some(path)) => Endpoint::Unix(path),
+                _ => {
+                    eprintln!("Expected either a host:port or a Unix socket to connect to");
+                    process::exit(1);
+                }
+            };
+            // Watching goes on until interrupted
+            if let cli::ClientCommand::Watch { hash } = command {
+                if core.run(client::watch(&endpoint, hash, &handle)).is_err() {
+                    process::exit(1);
+                }
+                return;
+            }
+            // Get Message structures from command line arguments
+            let msgs = match command.to_messages() {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    eprintln!("Invalid request: {}", e);
+                    process::exit(1);
+                }
+            };
             // TODO: Timeout? Try all addresses?
-            let future = client::request(&connect.0[0], msg.clone(), &handle);
-            core.run(future).unwrap();
+            let future = client::request(&endpoint, msgs, &handle);
+            if core.run(future).is_err() {
+                process::exit(1);
+            }
         }
     }
 }
@@ -1,12 +1,17 @@
+use std::collections::hash_map::RandomState;
 use std::error::Error;
 use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::iter;
 use std::str::FromStr;
 use std::io;
 use std::marker::Sized;
 use std::net::{IpAddr, SocketAddr};
 use std::str;
+use bytes::BytesMut;
+use sha2::{Digest, Sha256};
 use tokio_core::net::UdpCodec;
+use tokio_io::codec::{Decoder, Encoder};
 
 /// A Pushable object can be encoded and decoded from a frame
 pub trait Pushable {
@@ -25,15 +30,109 @@ macro_rules! pull {
     ($buf:expr, $range:expr) => {$buf.get($range).ok_or(DecodeError::MessageTooShort)}
 }
 
-/// Hashes are 8 bytes long
+/// Legacy hashes are 8 bytes long
 const HASH_SIZE: usize = 8;
 
+/// The longest digest a hash can hold
+const MAX_DIGEST_SIZE: usize = 32;
+
+/// The largest frame that fits in a UDP datagram
+pub const MAX_FRAME: usize = 65_507;
+
+/// The largest payload a PUT can carry
+/// The frame also holds the version header, the message type, the request ID, the hash with its
+/// tag and length, the payload length and the time to live
+pub const MAX_PAYLOAD: usize = MAX_FRAME - (1 + 1 + 4 + 2 + MAX_DIGEST_SIZE + 2 + 4);
+
+/// The protocol version this node speaks
+/// Version 0 is the legacy framing, where frames start with the message type
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Set on the first byte of frames starting with a version header
+/// Message types never have it, so legacy frames are still told apart
+const VERSIONED: u8 = 0x80;
+
+/// Hash functions content-addressed hashes come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Algorithm {
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// BLAKE3, with a 256-bit output
+    Blake3,
+}
+
+impl Algorithm {
+    /// The multihash code of this algorithm, tagging hashes on the wire
+    fn tag(self) -> u8 {
+        match self {
+            Algorithm::Sha256 => 0x12,
+            Algorithm::Blake3 => 0x1e,
+        }
+    }
+
+    /// Find the algorithm a tag stands for
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0x12 => Some(Algorithm::Sha256),
+            0x1e => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// The name of this algorithm, prefixing hashes in their textual form
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Compute the digest of some data
+    fn digest(self, data: &[u8]) -> [u8; MAX_DIGEST_SIZE] {
+        match self {
+            Algorithm::Sha256 => Sha256::digest(data).into(),
+            Algorithm::Blake3 => *blake3::hash(data).as_bytes(),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = AlgorithmParseError;
+
+    fn from_str(s: &str) -> Result<Algorithm, AlgorithmParseError> {
+        match s {
+            "sha256" => Ok(Algorithm::Sha256),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(AlgorithmParseError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlgorithmParseError;
+
+impl fmt::Display for AlgorithmParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("expected one of sha256 or blake3")
+    }
+}
+
+impl Error for AlgorithmParseError {}
+
 /// Stores a hash
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Hash([u8; HASH_SIZE]);
+/// Legacy hashes are 8 bytes picked freely, content-addressed ones are the digest of their
+/// content and carry the algorithm that computed it
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash {
+    /// None for legacy hashes
+    algorithm: Option<Algorithm>,
+    /// The hash bytes, padded with zeros
+    digest: [u8; MAX_DIGEST_SIZE],
+}
 
 impl Hash {
-    /// Create a new Hash
+    /// Create a new legacy Hash
     ///
     /// # Examples
     ///
@@ -42,53 +141,172 @@ impl Hash {
     /// Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
     /// ```
     pub fn new(hash: [u8; HASH_SIZE]) -> Self {
-        Hash(hash)
+        let mut digest = [0; MAX_DIGEST_SIZE];
+        digest[..HASH_SIZE].copy_from_slice(&hash);
+        Hash {
+            algorithm: None,
+            digest,
+        }
     }
 
+    /// Create a legacy Hash from the first 8 bytes of a slice
     pub fn from_slice(hash: &[u8]) -> Option<Self> {
         hash.get(..HASH_SIZE)
             .map(|h| Hash::new([h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7]]))
     }
+
+    /// The content-addressed hash of some data
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use simple_dht::messages::{Algorithm, Hash};
+    /// let hash = Hash::of_content(Algorithm::Sha256, b"hello");
+    /// assert!(hash.is_content());
+    /// assert!(hash.matches(b"hello"));
+    /// assert!(!hash.matches(b"world"));
+    /// ```
+    pub fn of_content(algorithm: Algorithm, data: &[u8]) -> Self {
+        Hash {
+            algorithm: Some(algorithm),
+            digest: algorithm.digest(data),
+        }
+    }
+
+    /// The algorithm this hash comes from, None for legacy hashes
+    pub fn algorithm(&self) -> Option<Algorithm> {
+        self.algorithm
+    }
+
+    /// The hash bytes
+    pub fn digest(&self) -> &[u8] {
+        match self.algorithm {
+            Some(_) => &self.digest,
+            None => &self.digest[..HASH_SIZE],
+        }
+    }
+
+    /// Check if this hash is derived from its content
+    pub fn is_content(&self) -> bool {
+        self.algorithm.is_some()
+    }
+
+    /// Check if some data may be stored under this hash
+    /// Anything goes for legacy hashes, content-addressed ones need their own content
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self.algorithm {
+            Some(algorithm) => algorithm.digest(data) == self.digest,
+            None => true,
+        }
+    }
+
+    /// Create a random Hash
+    pub fn random() -> Self {
+        // RandomState is seeded randomly for each process
+        Hash::from(RandomState::new().build_hasher().finish())
+    }
+
+    /// The XOR distance between two hashes
+    /// Only the first 64 bits of wider hashes count
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use simple_dht::messages::Hash;
+    /// let a = Hash::from(0b1100);
+    /// assert_eq!(a.distance(&Hash::from(0b1010)), 0b0110);
+    /// assert_eq!(a.distance(&a), 0);
+    /// ```
+    pub fn distance(&self, other: &Hash) -> u64 {
+        u64::from(*self) ^ u64::from(*other)
+    }
+}
+
+impl From<u64> for Hash {
+    fn from(value: u64) -> Self {
+        Hash::new(value.to_be_bytes())
+    }
+}
+
+// Wider hashes are placed in the 64-bit key space by their first bytes
+impl From<Hash> for u64 {
+    fn from(hash: Hash) -> u64 {
+        let mut bytes = [0; HASH_SIZE];
+        bytes.copy_from_slice(&hash.digest[..HASH_SIZE]);
+        u64::from_be_bytes(bytes)
+    }
+}
+
+/// Nodes are identified in the same space as hashes
+/// Node IDs are always legacy hashes
+pub type NodeId = Hash;
+
+/// Decode hexadecimal digits into bytes
+/// Missing leading digits are taken as zeros
+fn from_hex(s: &str, bytes: &mut [u8]) -> Result<(), HashParseError> {
+    let count = s.chars().count();
+    if count > bytes.len() * 2 {
+        return Err(HashParseError);
+    }
+
+    // Fill with leading zeros
+    let chars: Vec<char> = iter::repeat_n('0', bytes.len() * 2 - count)
+        .chain(s.chars())
+        .collect();
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        if let (Some(upper), Some(lower)) =
+            (chars[i * 2].to_digit(16), chars[i * 2 + 1].to_digit(16))
+        {
+            *byte = (lower + (upper << 4)) as u8;
+        } else {
+            return Err(HashParseError);
+        }
+    }
+    Ok(())
 }
 
 impl FromStr for Hash {
     type Err = HashParseError;
 
     /// Create a Hash from a string
+    /// Content-addressed hashes are prefixed by their algorithm, and hold a full digest
     ///
     /// # Examples
     ///
     /// ```
     /// use std::str::FromStr;
-    /// use simple_dht::messages::Hash;
+    /// use simple_dht::messages::{Algorithm, Hash};
     /// assert_eq!(Hash::from_str("0123456789abcdef"),
     ///            Ok(Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef])));
+    /// let hash = Hash::of_content(Algorithm::Blake3, b"hello");
+    /// assert_eq!(Hash::from_str(&format!("{:?}", hash)), Ok(hash));
     /// ```
     fn from_str(s: &str) -> Result<Hash, HashParseError> {
-        let count = s.chars().count();
-        if count > HASH_SIZE * 2 {
-            return Err(HashParseError);
-        }
-
-        // Fill with leading zeros
-        let chars: Vec<char> = iter::repeat('0')
-            .take(HASH_SIZE * 2 - count)
-            .chain(s.chars())
-            .collect();
-
-        let mut hash = [0; HASH_SIZE];
+        let (name, hex) = match s.find(':') {
+            Some(i) => (Some(&s[..i]), &s[(i + 1)..]),
+            None => (None, s),
+        };
 
-        for i in 0..HASH_SIZE {
-            if let (Some(upper), Some(lower)) =
-                (chars[i * 2].to_digit(16), chars[i * 2 + 1].to_digit(16))
-            {
-                hash[i] = (lower + (upper << 4)) as u8;
-            } else {
-                return Err(HashParseError);
+        match name {
+            Some(name) => {
+                let algorithm = Algorithm::from_str(name).map_err(|_| HashParseError)?;
+                if hex.chars().count() != MAX_DIGEST_SIZE * 2 {
+                    return Err(HashParseError);
+                }
+                let mut digest = [0; MAX_DIGEST_SIZE];
+                from_hex(hex, &mut digest)?;
+                Ok(Hash {
+                    algorithm: Some(algorithm),
+                    digest,
+                })
+            }
+            None => {
+                let mut hash = [0; HASH_SIZE];
+                from_hex(hex, &mut hash)?;
+                Ok(Hash::new(hash))
             }
         }
-
-        Ok(Hash::new(hash))
     }
 }
 
@@ -97,46 +315,96 @@ pub struct HashParseError;
 
 impl fmt::Display for HashParseError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.write_str(self.description())
+        fmt.write_str("invalid hash syntax")
     }
 }
 
-impl Error for HashParseError {
-    fn description(&self) -> &str {
-        "invalid hash syntax"
+impl Error for HashParseError {}
+
+// Hashes are encoded multihash-style: the algorithm tag, the digest length and the digest
+// Legacy hashes are tagged as identity hashes (0)
+impl Pushable for Hash {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        frame.push(self.algorithm.map_or(0, Algorithm::tag));
+        frame.push(self.digest().len() as u8);
+        frame.extend_from_slice(self.digest());
+    }
+
+    fn frame_len(&self) -> usize {
+        2 + self.digest().len()
+    }
+
+    fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
+        let header = pull!(buf, ..2)?;
+        let algorithm = match header[0] {
+            0 => None,
+            tag => Some(Algorithm::from_tag(tag).ok_or(DecodeError::InvalidContent)?),
+        };
+        let size = if algorithm.is_some() {
+            MAX_DIGEST_SIZE
+        } else {
+            HASH_SIZE
+        };
+        if header[1] as usize != size {
+            return Err(DecodeError::InvalidContent);
+        }
+
+        let bytes = pull!(buf, 2..(2 + size))?;
+        let mut digest = [0; MAX_DIGEST_SIZE];
+        digest[..size].copy_from_slice(bytes);
+        Ok(Hash { algorithm, digest })
     }
 }
 
-impl Pushable for Hash {
+/// The 8-byte form of a hash, used by legacy messages and for node IDs
+/// Wider hashes don't fit in it, and are truncated
+struct Legacy(Hash);
+
+impl Pushable for Legacy {
     fn push_in_frame(&self, frame: &mut Vec<u8>) {
-        frame.extend_from_slice(&self.0);
+        frame.extend_from_slice(&self.0.digest[..HASH_SIZE]);
     }
 
     fn frame_len(&self) -> usize {
-        HASH_SIZE as usize
+        HASH_SIZE
     }
 
     fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
-        pull!(buf, ..HASH_SIZE).map(|hash| Hash::from_slice(hash).unwrap())
+        pull!(buf, ..HASH_SIZE).map(|hash| Legacy(Hash::from_slice(hash).unwrap()))
     }
 }
 
 impl fmt::Debug for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        for i in 0..HASH_SIZE {
-            write!(f, "{:02x}", self.0[i])?;
+        if let Some(algorithm) = self.algorithm {
+            write!(f, "{}:", algorithm.name())?;
+        }
+        for byte in self.digest() {
+            write!(f, "{:02x}", byte)?;
         }
         Ok(())
     }
 }
 
 /// The payload inside PUT messages
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Payload(pub Vec<u8>);
 
-impl Default for Payload {
-    fn default() -> Self {
-        Payload(Vec::new())
+impl Payload {
+    /// Create a payload, checking it fits in a PUT message
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use simple_dht::messages::{Payload, MAX_PAYLOAD};
+    /// assert!(Payload::new(vec![0; MAX_PAYLOAD]).is_ok());
+    /// assert!(Payload::new(vec![0; MAX_PAYLOAD + 1]).is_err());
+    /// ```
+    pub fn new(data: Vec<u8>) -> Result<Self, EncodeError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(EncodeError::PayloadTooLarge(data.len()));
+        }
+        Ok(Payload(data))
     }
 }
 
@@ -147,9 +415,9 @@ impl fmt::Display for Payload {
 }
 
 impl FromStr for Payload {
-    type Err = io::Error;
-    fn from_str(s: &str) -> Result<Payload, io::Error> {
-        Ok(Payload(s.as_bytes().to_vec()))
+    type Err = EncodeError;
+    fn from_str(s: &str) -> Result<Payload, EncodeError> {
+        Payload::new(s.as_bytes().to_vec())
     }
 }
 
@@ -162,7 +430,7 @@ impl Pushable for Payload {
     }
 
     fn frame_len(&self) -> usize {
-        (self.0.len() + 2) as usize
+        self.0.len() + 2
     }
 
     fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
@@ -177,6 +445,71 @@ impl Pushable for Payload {
     }
 }
 
+/// Error codes carried by ERROR messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request could not be decoded
+    InvalidMessage,
+    /// The request was decoded, but its content makes no sense
+    InvalidRequest,
+    /// The server failed to process a valid request
+    Internal,
+    /// A code this implementation does not know about
+    Other(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::InvalidMessage,
+            2 => ErrorCode::InvalidRequest,
+            3 => ErrorCode::Internal,
+            c => ErrorCode::Other(c),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> u8 {
+        match code {
+            ErrorCode::InvalidMessage => 1,
+            ErrorCode::InvalidRequest => 2,
+            ErrorCode::Internal => 3,
+            ErrorCode::Other(c) => c,
+        }
+    }
+}
+
+impl Pushable for ErrorCode {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        frame.push(u8::from(*self));
+    }
+
+    fn frame_len(&self) -> usize {
+        1
+    }
+
+    fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
+        u8::pull(buf).map(ErrorCode::from)
+    }
+}
+
+// Strings are encoded like payloads, and must be valid UTF-8
+impl Pushable for String {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        Payload(self.as_bytes().to_vec()).push_in_frame(frame);
+    }
+
+    fn frame_len(&self) -> usize {
+        self.len() + 2
+    }
+
+    fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
+        let Payload(bytes) = Payload::pull(buf)?;
+        String::from_utf8(bytes).map_err(|_| DecodeError::InvalidContent)
+    }
+}
+
 // The message type is stored as a u8
 impl Pushable for u8 {
     fn push_in_frame(&self, frame: &mut Vec<u8>) {
@@ -192,6 +525,31 @@ impl Pushable for u8 {
     }
 }
 
+// An optional time to live ends a message: it is either there or not
+impl Pushable for Option<u32> {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        if let Some(ttl) = *self {
+            frame.extend_from_slice(&ttl.to_be_bytes());
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        if self.is_some() {
+            4
+        } else {
+            0
+        }
+    }
+
+    fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let bytes = pull!(buf, ..4)?;
+        Ok(Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])))
+    }
+}
+
 impl Pushable for SocketAddr {
     fn push_in_frame(&self, frame: &mut Vec<u8>) {
         match *self {
@@ -241,65 +599,396 @@ impl Pushable for SocketAddr {
     }
 }
 
+// Lists of nodes are prefixed by their length, on one byte
+impl Pushable for Vec<(NodeId, SocketAddr)> {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        frame.push(self.len().min(u8::MAX as usize) as u8);
+        for &(id, addr) in self.iter().take(u8::MAX as usize) {
+            Legacy(id).push_in_frame(frame);
+            addr.push_in_frame(frame);
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        self.iter()
+            .take(u8::MAX as usize)
+            .fold(1, |len, (_, addr)| len + HASH_SIZE + addr.frame_len())
+    }
+
+    fn pull(buf: &[u8]) -> Result<Self, DecodeError> {
+        let count = u8::pull(buf)?;
+        let mut offset = 1;
+        let mut nodes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let Legacy(id) = Legacy::pull(pull!(buf, offset..)?)?;
+            offset += HASH_SIZE;
+            let addr = SocketAddr::pull(pull!(buf, offset..)?)?;
+            offset += addr.frame_len();
+            nodes.push((id, addr));
+        }
+        Ok(nodes)
+    }
+}
+
+/// What a node supports, advertised by HELLO messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The latest protocol version the node speaks
+    pub version: u8,
+    /// Algorithms of the content-addressed hashes the node accepts
+    pub algorithms: Vec<Algorithm>,
+    /// Codes of the compression schemes the node can decode
+    /// None is defined yet: nodes advertise an empty list, and never compress
+    pub compression: Vec<u8>,
+    /// The largest PUT payload the node accepts
+    pub max_payload: u16,
+}
+
+impl Capabilities {
+    /// What this node supports
+    pub fn local() -> Self {
+        Capabilities {
+            version: PROTOCOL_VERSION,
+            algorithms: vec![Algorithm::Sha256, Algorithm::Blake3],
+            compression: Vec::new(),
+            max_payload: MAX_PAYLOAD as u16,
+        }
+    }
+
+    /// What both nodes support
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use simple_dht::messages::{Algorithm, Capabilities};
+    /// let old = Capabilities {
+    ///     version: 1,
+    ///     algorithms: vec![Algorithm::Sha256],
+    ///     compression: Vec::new(),
+    ///     max_payload: 1024,
+    /// };
+    /// assert_eq!(Capabilities::local().common(&old), old);
+    /// ```
+    pub fn common(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            version: self.version.min(other.version),
+            algorithms: self.algorithms
+                .iter()
+                .filter(|algorithm| other.algorithms.contains(algorithm))
+                .cloned()
+                .collect(),
+            compression: self.compression
+                .iter()
+                .filter(|code| other.compression.contains(code))
+                .cloned()
+                .collect(),
+            max_payload: self.max_payload.min(other.max_payload),
+        }
+    }
+
+    /// Check if a message can be sent to a node with these capabilities
+    pub fn accepts(&self, msg: &Message) -> bool {
+        let algorithm = msg.hash().and_then(|hash| hash.algorithm());
+        if algorithm.is_some_and(|algorithm| !self.algorithms.contains(&algorithm)) {
+            return false;
+        }
+        match *msg {
+            Message::Put(_, ref payload, _) => payload.0.len() <= self.max_payload as usize,
+            _ => true,
+        }
+    }
+
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        frame.push(self.version);
+        frame.push(self.algorithms.len() as u8);
+        frame.extend(self.algorithms.iter().map(|algorithm| algorithm.tag()));
+        frame.push(self.compression.len() as u8);
+        frame.extend_from_slice(&self.compression);
+        frame.extend_from_slice(&self.max_payload.to_be_bytes());
+    }
+
+    fn frame_len(&self) -> usize {
+        1 + 1 + self.algorithms.len() + 1 + self.compression.len() + 2
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Get(Hash),
-    Put(Hash, Payload),
-    KeepAlive,
+    /// A hash content, and how many seconds it should be kept
+    /// Without a time to live, the receiver's default is used
+    Put(Hash, Payload, Option<u32>),
+    KeepAlive(NodeId),
     IHave(Hash),
     Discover(SocketAddr),
+    NotFound(Hash),
+    Error(ErrorCode, String),
+    /// Ask for the nodes closest to an ID
+    FindNode(NodeId),
+    /// Ask for a hash, or the nodes closest to it
+    FindValue(Hash),
+    /// The nodes closest to a hash or an ID
+    Nodes(Hash, Vec<(NodeId, SocketAddr)>),
+    /// How many replicas hold or answered with a hash, and how many were required
+    Quorum(Hash, u8, u8),
+    /// The capabilities of the sender, answered with the ones of the receiver
+    Hello(Capabilities),
+    /// Remove a hash, answered with the same message once the receiver removed it
+    Delete(Hash),
+    /// Be told of every PUT or DELETE of a hash until the lease runs out, renewed by sending it
+    /// again. Answered with the same message, then with the notifications
+    Watch(Hash),
+    /// Stop watching a hash, answered with the same message
+    Unwatch(Hash),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DecodeError {
     MessageTooLong,
     MessageTooShort,
     InvalidMessageType,
     InvalidContent,
+    UnsupportedVersion,
 }
 
 impl fmt::Display for DecodeError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "{}", Error::description(self))
-    }
-}
-
-impl Error for DecodeError {
-    fn description(&self) -> &str {
-        match *self {
+        fmt.write_str(match *self {
             DecodeError::MessageTooLong => "input message is too long",
             DecodeError::MessageTooShort => "input message is too short",
             DecodeError::InvalidMessageType => "message type unknown",
             DecodeError::InvalidContent => "invalid message content",
-        }
+            DecodeError::UnsupportedVersion => "protocol version unsupported",
+        })
     }
 }
 
+impl Error for DecodeError {}
+
 impl From<DecodeError> for io::Error {
     fn from(src: DecodeError) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, src)
+        io::Error::other(src)
+    }
+}
+
+/// Why a message can't be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// A PUT payload is larger than `MAX_PAYLOAD`, with its actual size
+    PayloadTooLarge(usize),
+    /// The whole frame is larger than `MAX_FRAME`, with its actual size
+    MessageTooLong(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::PayloadTooLarge(len) => write!(
+                fmt,
+                "payload of {} bytes exceeds the {} bytes limit",
+                len, MAX_PAYLOAD
+            ),
+            EncodeError::MessageTooLong(len) => write!(
+                fmt,
+                "message of {} bytes exceeds the {} bytes limit",
+                len, MAX_FRAME
+            ),
+        }
     }
 }
 
+impl Error for EncodeError {}
+
+impl From<EncodeError> for io::Error {
+    fn from(src: EncodeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, src)
+    }
+}
+
+/// Encodes and decodes messages in UDP datagrams
+/// Datagrams that can't be decoded are yielded as errors along with their source, so that a
+/// single invalid datagram doesn't end the stream. Messages are encoded beforehand, since the
+/// codec can't fail: those that can't be are never handed to it
 pub struct UdpMessage;
 
 impl UdpCodec for UdpMessage {
-    type In = (SocketAddr, Message);
-    type Out = (SocketAddr, Message);
+    type In = (SocketAddr, Result<(Option<RequestId>, Message), DecodeError>);
+    type Out = (SocketAddr, Vec<u8>);
 
     fn decode(&mut self, addr: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        match Message::deserialize(buf) {
-            Ok(msg) => Ok((*addr, msg)),
-            Err(e) => Err(e.into()),
-        }
+        Ok((*addr, Message::deserialize_request(buf)))
     }
 
-    fn encode(&mut self, (addr, msg): Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
-        buf.extend(msg.serialize());
+    fn encode(&mut self, (addr, frame): Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
+        buf.extend(frame);
         addr
     }
 }
 
+/// Frames messages on a stream, each one following its length as a big-endian u32
+/// Frames that can't be decoded are yielded as errors, the stream only fails on lengths no
+/// message could have. Messages are encoded beforehand, in the version the other end speaks
+pub struct TcpMessage;
+
+impl Decoder for TcpMessage {
+    type Item = Result<(Option<RequestId>, Message), DecodeError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len > MAX_FRAME {
+            return Err(DecodeError::MessageTooLong.into());
+        }
+        if buf.len() < 4 + len {
+            buf.reserve(4 + len - buf.len());
+            return Ok(None);
+        }
+
+        buf.split_to(4);
+        let frame = buf.split_to(len);
+        Ok(Some(Message::deserialize_request(&frame)))
+    }
+}
+
+impl Encoder for TcpMessage {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Vec<u8>, buf: &mut BytesMut) -> io::Result<()> {
+        buf.reserve(4 + frame.len());
+        buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+/// Set on the message type when its hashes are tagged with their algorithm
+/// Messages holding only legacy hashes are encoded as they always were
+const TAGGED_HASHES: u8 = 0x40;
+
+/// Set on the message type when a request ID follows it
+const REQUEST_ID: u8 = 0x20;
+
+/// Identifies a request, echoed by the responses to it so that they can be told apart
+pub type RequestId = u32;
+
+/// A hash inside a message, either tagged or in its legacy form
+struct HashField(Hash, bool);
+
+impl HashField {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        if self.1 {
+            self.0.push_in_frame(frame);
+        } else {
+            Legacy(self.0).push_in_frame(frame);
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        if self.1 {
+            self.0.frame_len()
+        } else {
+            HASH_SIZE
+        }
+    }
+}
+
+/// The version header of a frame, left out for version 0
+struct Header(u8);
+
+impl Header {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        if self.0 > 0 {
+            frame.push(VERSIONED | self.0);
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        if self.0 > 0 {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// The message type, followed by the request ID if there is one
+struct RequestHeader(u8, Option<RequestId>);
+
+impl RequestHeader {
+    fn push_in_frame(&self, frame: &mut Vec<u8>) {
+        frame.push(self.0);
+        if let Some(request) = self.1 {
+            frame.extend_from_slice(&request.to_be_bytes());
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        1 + self.1.map_or(0, |_| 4)
+    }
+}
+
+/// Reads the parts of a message one after the other
+struct Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    /// Whether the hashes are tagged
+    tagged: bool,
+}
+
+impl<'a> Reader<'a> {
+    /// Pull the next part
+    fn pull<T: Pushable>(&mut self) -> Result<T, DecodeError> {
+        let value = T::pull(pull!(self.buf, self.offset..)?)?;
+        self.offset += value.frame_len();
+        Ok(value)
+    }
+
+    /// Pull the next hash
+    fn hash(&mut self) -> Result<Hash, DecodeError> {
+        if self.tagged {
+            self.pull()
+        } else {
+            self.node()
+        }
+    }
+
+    /// Pull the next node ID
+    fn node(&mut self) -> Result<NodeId, DecodeError> {
+        self.pull().map(|Legacy(id)| id)
+    }
+
+    /// Pull the capabilities of a node
+    /// Algorithms unknown to this node are skipped
+    fn capabilities(&mut self) -> Result<Capabilities, DecodeError> {
+        let version = self.pull()?;
+        let count: u8 = self.pull()?;
+        let mut algorithms = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            if let Some(algorithm) = Algorithm::from_tag(self.pull()?) {
+                algorithms.push(algorithm);
+            }
+        }
+        let count: u8 = self.pull()?;
+        let mut compression = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            compression.push(self.pull()?);
+        }
+        let max_payload = pull!(self.buf, self.offset..(self.offset + 2))?;
+        let max_payload = u16::from_be_bytes([max_payload[0], max_payload[1]]);
+        self.offset += 2;
+        Ok(Capabilities {
+            version,
+            algorithms,
+            compression,
+            max_payload,
+        })
+    }
+}
+
 /// Build a message from list of parts
 macro_rules! build_msg {
     ($( $x:expr ),*) => ({
@@ -316,67 +1005,213 @@ macro_rules! build_msg {
 }
 
 impl Message {
-    /// Serialize a message into a vector of bytes
+    /// Serialize a message into a vector of bytes, in the legacy framing every node understands
+    /// Fails if the message doesn't fit in a datagram
     ///
     /// ```
-    /// use simple_dht::messages::Message;
-    /// let buf = Message::KeepAlive.serialize();
-    /// assert_eq!(buf, vec![2]);
+    /// use simple_dht::messages::{Hash, Message};
+    /// let buf = Message::KeepAlive(Hash::from(42)).serialize().unwrap();
+    /// assert_eq!(buf, vec![2, 0, 0, 0, 0, 0, 0, 0, 42]);
     /// ```
-    pub fn serialize(&self) -> Vec<u8> {
-        let id = self.type_identifier();
-        match *self {
-            Message::Get(ref hash) => build_msg!(id, hash),
-            Message::Put(ref hash, ref payload) => build_msg!(id, hash, payload),
-            Message::KeepAlive => build_msg!(id),
-            Message::IHave(ref hash) => build_msg!(id, hash),
-            Message::Discover(ref addr) => build_msg!(id, addr),
+    pub fn serialize(&self) -> Result<Vec<u8>, EncodeError> {
+        self.serialize_for(0, None)
+    }
+
+    /// Serialize a message for a node speaking the given protocol version, along with the ID
+    /// of the request it belongs to
+    /// Frames start with a version header, unless the node only speaks version 0
+    ///
+    /// ```
+    /// use simple_dht::messages::{Hash, Message};
+    /// let buf = Message::KeepAlive(Hash::from(42)).serialize_for(1, None).unwrap();
+    /// assert_eq!(buf, vec![0x81, 2, 0, 0, 0, 0, 0, 0, 0, 42]);
+    /// let buf = Message::KeepAlive(Hash::from(42)).serialize_for(0, Some(7)).unwrap();
+    /// assert_eq!(buf, vec![0x22, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 42]);
+    /// ```
+    pub fn serialize_for(
+        &self,
+        version: u8,
+        request: Option<RequestId>,
+    ) -> Result<Vec<u8>, EncodeError> {
+        if let Message::Put(_, ref payload, _) = *self {
+            if payload.0.len() > MAX_PAYLOAD {
+                return Err(EncodeError::PayloadTooLarge(payload.0.len()));
+            }
+        }
+
+        let tagged = self.has_tagged_hashes();
+        let mut id = self.type_identifier();
+        if tagged {
+            id |= TAGGED_HASHES;
+        }
+        if request.is_some() {
+            id |= REQUEST_ID;
         }
+        let id = RequestHeader(id, request);
+        let hash = |hash: &Hash| HashField(*hash, tagged);
+        let node = |id: &NodeId| Legacy(*id);
+        let header = Header(version.min(PROTOCOL_VERSION));
+        let frame = match *self {
+            Message::Get(ref h) => build_msg!(header, id, hash(h)),
+            Message::Put(ref h, ref payload, ref ttl) => {
+                build_msg!(header, id, hash(h), payload, ttl)
+            }
+            Message::KeepAlive(ref n) => build_msg!(header, id, node(n)),
+            Message::IHave(ref h) => build_msg!(header, id, hash(h)),
+            Message::Discover(ref addr) => build_msg!(header, id, addr),
+            Message::NotFound(ref h) => build_msg!(header, id, hash(h)),
+            Message::Error(ref code, ref reason) => build_msg!(header, id, code, reason),
+            Message::FindNode(ref n) => build_msg!(header, id, node(n)),
+            Message::FindValue(ref h) => build_msg!(header, id, hash(h)),
+            Message::Nodes(ref h, ref nodes) => build_msg!(header, id, hash(h), nodes),
+            Message::Quorum(ref h, ref replicas, ref required) => {
+                build_msg!(header, id, hash(h), replicas, required)
+            }
+            Message::Hello(ref capabilities) => build_msg!(header, id, capabilities),
+            Message::Delete(ref h) => build_msg!(header, id, hash(h)),
+            Message::Watch(ref h) => build_msg!(header, id, hash(h)),
+            Message::Unwatch(ref h) => build_msg!(header, id, hash(h)),
+        };
+
+        if frame.len() > MAX_FRAME {
+            return Err(EncodeError::MessageTooLong(frame.len()));
+        }
+        Ok(frame)
     }
 
     /// Deserialize a buffer into a message
+    /// Frames may start with a version header, up to the version this node speaks
     ///
     /// ```
-    /// use simple_dht::messages::Message;
-    /// let buf = &[2];
-    /// assert_eq!(Message::deserialize(buf), Ok(Message::KeepAlive));
+    /// use simple_dht::messages::{Hash, Message};
+    /// let buf = &[2, 0, 0, 0, 0, 0, 0, 0, 42];
+    /// assert_eq!(Message::deserialize(buf), Ok(Message::KeepAlive(Hash::from(42))));
     /// ```
     pub fn deserialize(buf: &[u8]) -> Result<Self, DecodeError> {
-        let id = u8::pull(buf)?;
+        Message::deserialize_request(buf).map(|(_, msg)| msg)
+    }
 
-        let msg = match id {
-            0 => {
-                let hash = Hash::pull(&buf[1..])?;
-                Message::Get(hash)
+    /// Deserialize a buffer into a message, along with the ID of the request it belongs to
+    ///
+    /// ```
+    /// use simple_dht::messages::{Hash, Message};
+    /// let buf = &[0x22, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 42];
+    /// let msg = Message::KeepAlive(Hash::from(42));
+    /// assert_eq!(Message::deserialize_request(buf), Ok((Some(7), msg)));
+    /// ```
+    pub fn deserialize_request(buf: &[u8]) -> Result<(Option<RequestId>, Self), DecodeError> {
+        if buf.len() > MAX_FRAME {
+            return Err(DecodeError::MessageTooLong);
+        }
+        let mut offset = 0;
+        let first = u8::pull(buf)?;
+        if first & VERSIONED != 0 {
+            let version = first & !VERSIONED;
+            if version == 0 || version > PROTOCOL_VERSION {
+                return Err(DecodeError::UnsupportedVersion);
             }
+            offset += 1;
+        }
+
+        let id = u8::pull(pull!(buf, offset..)?)?;
+        offset += 1;
+        let request = if id & REQUEST_ID != 0 {
+            let bytes = pull!(buf, offset..(offset + 4))?;
+            offset += 4;
+            Some(RequestId::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        } else {
+            None
+        };
+        let mut reader = Reader {
+            buf,
+            offset,
+            tagged: id & TAGGED_HASHES != 0,
+        };
+
+        let msg = match id & !(TAGGED_HASHES | REQUEST_ID) {
+            0 => Message::Get(reader.hash()?),
             1 => {
-                let hash = Hash::pull(&buf[1..])?;
-                let payload = Payload::pull(&buf[(1 + HASH_SIZE)..])?;
-                Message::Put(hash, payload)
+                let hash = reader.hash()?;
+                let payload = reader.pull()?;
+                let ttl = reader.pull()?;
+                Message::Put(hash, payload, ttl)
             }
-            2 => Message::KeepAlive,
-            3 => {
-                let hash = Hash::pull(&buf[1..])?;
-                Message::IHave(hash)
+            2 => Message::KeepAlive(reader.node()?),
+            3 => Message::IHave(reader.hash()?),
+            4 => Message::Discover(reader.pull()?),
+            5 => Message::NotFound(reader.hash()?),
+            6 => {
+                let code = reader.pull()?;
+                let reason = reader.pull()?;
+                Message::Error(code, reason)
             }
-            4 => {
-                let addr = SocketAddr::pull(&buf[1..])?;
-                Message::Discover(addr)
+            7 => Message::FindNode(reader.node()?),
+            8 => Message::FindValue(reader.hash()?),
+            9 => {
+                let hash = reader.hash()?;
+                let nodes = reader.pull()?;
+                Message::Nodes(hash, nodes)
+            }
+            10 => {
+                let hash = reader.hash()?;
+                let replicas = reader.pull()?;
+                let required = reader.pull()?;
+                Message::Quorum(hash, replicas, required)
             }
+            11 => Message::Hello(reader.capabilities()?),
+            12 => Message::Delete(reader.hash()?),
+            13 => Message::Watch(reader.hash()?),
+            14 => Message::Unwatch(reader.hash()?),
             _ => return Err(DecodeError::InvalidMessageType),
         };
 
-        Ok(msg)
+        // Nothing may follow the message
+        if reader.offset < buf.len() {
+            return Err(DecodeError::MessageTooLong);
+        }
+
+        Ok((request, msg))
     }
 
+    /// The hash the message is about, if any
+    fn hash(&self) -> Option<&Hash> {
+        match *self {
+            Message::Get(ref hash)
+            | Message::Put(ref hash, _, _)
+            | Message::IHave(ref hash)
+            | Message::NotFound(ref hash)
+            | Message::FindValue(ref hash)
+            | Message::Nodes(ref hash, _)
+            | Message::Quorum(ref hash, _, _)
+            | Message::Delete(ref hash)
+            | Message::Watch(ref hash)
+            | Message::Unwatch(ref hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// Check if the message holds content-addressed hashes, which don't fit in the legacy form
+    fn has_tagged_hashes(&self) -> bool {
+        self.hash().is_some_and(Hash::is_content)
+    }
     /// The message type -> id conversion
     fn type_identifier(&self) -> u8 {
         match *self {
             Message::Get(_) => 0,
-            Message::Put(_, _) => 1,
-            Message::KeepAlive => 2,
+            Message::Put(_, _, _) => 1,
+            Message::KeepAlive(_) => 2,
             Message::IHave(_) => 3,
             Message::Discover(_) => 4,
+            Message::NotFound(_) => 5,
+            Message::Error(_, _) => 6,
+            Message::FindNode(_) => 7,
+            Message::FindValue(_) => 8,
+            Message::Nodes(_, _) => 9,
+            Message::Quorum(_, _, _) => 10,
+            Message::Hello(_) => 11,
+            Message::Delete(_) => 12,
+            Message::Watch(_) => 13,
+            Message::Unwatch(_) => 14,
         }
     }
 }
@@ -387,26 +1222,316 @@ mod tests {
 
     #[test]
     fn serialize_get() {
-        let hash = Hash([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
         assert_eq!(
-            Message::Get(hash).serialize(),
+            Message::Get(hash).serialize().unwrap(),
             [0x00, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
         );
     }
 
     #[test]
     fn serialize_put() {
-        let hash = Hash([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
         let payload = "Hello, world!".as_bytes();
 
-        let message = Message::Put(hash.clone(), Payload(payload.clone().to_vec()));
-        let frame = message.serialize();
+        let message = Message::Put(hash, Payload(payload.to_vec()), None);
+        let frame = message.serialize().unwrap();
         assert_eq!(frame[0], 1); // Check message type
-        assert_eq!(frame[1..(1 + HASH_SIZE)], hash.0); // Check hash
+        assert_eq!(frame[1..(1 + HASH_SIZE)], *hash.digest()); // Check hash
         assert_eq!(frame[(1 + HASH_SIZE)..(3 + HASH_SIZE)], [0, 13]); // Check payload length
         assert_eq!(frame[(3 + HASH_SIZE)..], *payload); // Check payload payload
     }
 
+    #[test]
+    fn roundtrip_put_ttl() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let message = Message::Put(hash, Payload(b"hi".to_vec()), Some(3600));
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame[(5 + HASH_SIZE)..], [0, 0, 0x0e, 0x10]);
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // A truncated time to live is rejected
+        assert_eq!(
+            Message::deserialize(&frame[..frame.len() - 1]),
+            Err(DecodeError::MessageTooShort)
+        );
+    }
+
+    #[test]
+    fn payload_limits() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+
+        // The largest payload still fits in a datagram, with a header, a request ID, a wide hash
+        // and a time to live
+        let wide = Hash::of_content(Algorithm::Sha256, b"");
+        let message = Message::Put(wide, Payload(vec![0; MAX_PAYLOAD]), Some(60));
+        let frame = message.serialize_for(PROTOCOL_VERSION, Some(42)).unwrap();
+        assert_eq!(frame.len(), MAX_FRAME);
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // Larger ones are rejected instead of corrupting the length
+        let large = Message::Put(hash, Payload(vec![0; 70_000]), None);
+        assert_eq!(large.serialize(), Err(EncodeError::PayloadTooLarge(70_000)));
+        assert!("a".repeat(MAX_PAYLOAD + 1).parse::<Payload>().is_err());
+
+        // So are the other messages too long for a datagram
+        let reason = "a".repeat(MAX_FRAME);
+        let error = Message::Error(ErrorCode::Internal, reason);
+        assert_eq!(error.serialize(), Err(EncodeError::MessageTooLong(MAX_FRAME + 4)));
+    }
+
+    #[test]
+    fn udp_frames() {
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let get = Message::Get(Hash::from(42));
+        let frame = get.serialize_for(0, Some(7)).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(UdpMessage.encode((addr, frame.clone()), &mut buf), addr);
+        assert_eq!(buf, frame);
+        assert_eq!(UdpMessage.decode(&addr, &buf).unwrap(), (addr, Ok((Some(7), get))));
+    }
+
+    #[test]
+    fn tcp_frames() {
+        let hash = Hash::from(42);
+        let get = Message::Get(hash).serialize_for(PROTOCOL_VERSION, Some(7)).unwrap();
+        let mut buf = BytesMut::new();
+        TcpMessage.encode(get.clone(), &mut buf).unwrap();
+        TcpMessage.encode(vec![0x1f], &mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + get.len() + 4 + 1);
+
+        // Frames are only decoded once whole, and invalid ones don't end the stream
+        let mut partial = BytesMut::from(&buf[..get.len()]);
+        assert_eq!(TcpMessage.decode(&mut partial).unwrap(), None);
+        assert_eq!(
+            TcpMessage.decode(&mut buf).unwrap(),
+            Some(Ok((Some(7), Message::Get(hash))))
+        );
+        assert_eq!(
+            TcpMessage.decode(&mut buf).unwrap(),
+            Some(Err(DecodeError::InvalidMessageType))
+        );
+        assert!(buf.is_empty());
+
+        // Lengths no message could have can't be skipped
+        let mut buf = BytesMut::from(&((MAX_FRAME + 1) as u32).to_be_bytes()[..]);
+        assert!(TcpMessage.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn decode_too_long() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let mut frame = Message::Get(hash).serialize().unwrap();
+        frame.push(0);
+        assert_eq!(Message::deserialize(&frame), Err(DecodeError::MessageTooLong));
+
+        let frame = vec![1; MAX_FRAME + 1];
+        assert_eq!(Message::deserialize(&frame), Err(DecodeError::MessageTooLong));
+    }
+
+    #[test]
+    fn roundtrip_not_found() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let frame = Message::NotFound(hash).serialize().unwrap();
+        assert_eq!(frame[0], 5);
+        assert_eq!(Message::deserialize(&frame), Ok(Message::NotFound(hash)));
+    }
+
+    #[test]
+    fn roundtrip_error() {
+        let message = Message::Error(ErrorCode::InvalidRequest, String::from("nope"));
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame, [6, 2, 0, 4, b'n', b'o', b'p', b'e']);
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // Unknown codes are kept as-is
+        let frame = [6, 42, 0, 0];
+        let expected = Message::Error(ErrorCode::Other(42), String::new());
+        assert_eq!(Message::deserialize(&frame), Ok(expected));
+    }
+
+    #[test]
+    fn roundtrip_nodes() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let nodes = vec![
+            (Hash::from(1), "127.0.0.1:4242".parse().unwrap()),
+            (Hash::from(2), "[::1]:4243".parse().unwrap()),
+        ];
+        let message = Message::Nodes(hash, nodes);
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame.len(), 1 + 8 + 1 + (8 + 7) + (8 + 19));
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // A truncated list is rejected
+        assert_eq!(
+            Message::deserialize(&frame[..frame.len() - 1]),
+            Err(DecodeError::MessageTooShort)
+        );
+
+        // Only the first 255 nodes fit in a list
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        let nodes: Vec<_> = (0..300).map(|id| (Hash::from(id), addr)).collect();
+        let frame = Message::Nodes(hash, nodes.clone()).serialize().unwrap();
+        assert_eq!(frame.len(), 1 + 8 + 1 + 255 * (8 + 7));
+        let expected = Message::Nodes(hash, nodes[..255].to_vec());
+        assert_eq!(Message::deserialize(&frame), Ok(expected));
+    }
+
+    #[test]
+    fn roundtrip_quorum() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let message = Message::Quorum(hash, 2, 3);
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame[0], 10);
+        assert_eq!(frame[(1 + HASH_SIZE)..], [2, 3]);
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+    }
+
+    #[test]
+    fn roundtrip_delete() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let frame = Message::Delete(hash).serialize().unwrap();
+        assert_eq!(frame, [12, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        assert_eq!(Message::deserialize(&frame), Ok(Message::Delete(hash)));
+
+        let content = Hash::of_content(Algorithm::Sha256, b"hello");
+        let frame = Message::Delete(content).serialize().unwrap();
+        assert_eq!(frame[0], 12 | TAGGED_HASHES);
+        assert_eq!(Message::deserialize(&frame), Ok(Message::Delete(content)));
+    }
+
+    #[test]
+    fn roundtrip_watch() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let frame = Message::Watch(hash).serialize().unwrap();
+        assert_eq!(frame, [13, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        assert_eq!(Message::deserialize(&frame), Ok(Message::Watch(hash)));
+
+        let content = Hash::of_content(Algorithm::Sha256, b"hello");
+        let frame = Message::Unwatch(content).serialize_for(1, Some(3)).unwrap();
+        assert_eq!(frame[1], 14 | TAGGED_HASHES | REQUEST_ID);
+        assert_eq!(Message::deserialize_request(&frame), Ok((Some(3), Message::Unwatch(content))));
+    }
+
+    #[test]
+    fn roundtrip_tagged() {
+        let hash = Hash::of_content(Algorithm::Sha256, b"hello");
+        let message = Message::Put(hash, Payload(b"hello".to_vec()), None);
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame[0], 1 | TAGGED_HASHES);
+        assert_eq!(frame[1..3], [0x12, 32]);
+        assert_eq!(frame[3..35], *hash.digest());
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // Node IDs keep their 8-byte form
+        let nodes = vec![(Hash::from(1), "127.0.0.1:4242".parse().unwrap())];
+        let message = Message::Nodes(Hash::of_content(Algorithm::Blake3, b""), nodes);
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame.len(), 1 + 34 + 1 + (8 + 7));
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // Unknown algorithms and digests of the wrong size are rejected
+        let mut unknown = Message::Get(hash).serialize().unwrap();
+        unknown[1] = 0x42;
+        assert_eq!(Message::deserialize(&unknown), Err(DecodeError::InvalidContent));
+        let mut short = Message::Get(hash).serialize().unwrap();
+        short[2] = 16;
+        assert_eq!(Message::deserialize(&short), Err(DecodeError::InvalidContent));
+
+        // Legacy hashes may be tagged too
+        let frame = [TAGGED_HASHES, 0, 8, 0, 0, 0, 0, 0, 0, 0, 42];
+        assert_eq!(Message::deserialize(&frame), Ok(Message::Get(Hash::from(42))));
+    }
+
+    #[test]
+    fn versioned_frames() {
+        let hash = Hash::new([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        let message = Message::Quorum(hash, 2, 3);
+        let legacy = message.serialize().unwrap();
+        let frame = message.serialize_for(PROTOCOL_VERSION, None).unwrap();
+        assert_eq!(frame[0], VERSIONED | PROTOCOL_VERSION);
+        assert_eq!(frame[1..], *legacy);
+        assert_eq!(Message::deserialize(&frame), Ok(message.clone()));
+        assert_eq!(Message::deserialize(&legacy), Ok(message.clone()));
+
+        // Nodes speaking a later version are answered in this one
+        assert_eq!(message.serialize_for(PROTOCOL_VERSION + 1, None), Ok(frame.clone()));
+
+        // Frames from a later version, or with a header for version 0, can't be read
+        let mut later = frame.clone();
+        later[0] = VERSIONED | (PROTOCOL_VERSION + 1);
+        assert_eq!(Message::deserialize(&later), Err(DecodeError::UnsupportedVersion));
+        assert_eq!(Message::deserialize(&[VERSIONED, 5, 0]), Err(DecodeError::UnsupportedVersion));
+        assert_eq!(Message::deserialize(&frame[..1]), Err(DecodeError::MessageTooShort));
+    }
+
+    #[test]
+    fn request_ids() {
+        let hash = Hash::of_content(Algorithm::Sha256, b"hello");
+        let message = Message::Put(hash, Payload(b"hello".to_vec()), Some(60));
+        let frame = message.serialize_for(PROTOCOL_VERSION, Some(0x0102_0304)).unwrap();
+        assert_eq!(frame[0], VERSIONED | PROTOCOL_VERSION);
+        assert_eq!(frame[1], 1 | TAGGED_HASHES | REQUEST_ID);
+        assert_eq!(frame[2..6], [1, 2, 3, 4]);
+        assert_eq!(frame[6..], message.serialize().unwrap()[1..]);
+        assert_eq!(Message::deserialize_request(&frame), Ok((Some(0x0102_0304), message.clone())));
+        assert_eq!(Message::deserialize(&frame), Ok(message.clone()));
+
+        // Messages without an ID are decoded as such
+        let frame = message.serialize().unwrap();
+        assert_eq!(Message::deserialize_request(&frame), Ok((None, message)));
+
+        // A truncated ID is rejected
+        assert_eq!(Message::deserialize(&[REQUEST_ID, 0, 0]), Err(DecodeError::MessageTooShort));
+    }
+
+    #[test]
+    fn roundtrip_hello() {
+        let message = Message::Hello(Capabilities::local());
+        let frame = message.serialize().unwrap();
+        assert_eq!(frame, [11, PROTOCOL_VERSION, 2, 0x12, 0x1e, 0, 0xff, 0xb5]);
+        assert_eq!(Message::deserialize(&frame), Ok(message));
+
+        // Algorithms unknown to this node are left out
+        let frame = [11, 2, 2, 0x1b, 0x12, 1, 7, 0x10, 0x00];
+        let expected = Capabilities {
+            version: 2,
+            algorithms: vec![Algorithm::Sha256],
+            compression: vec![7],
+            max_payload: 4096,
+        };
+        assert_eq!(Message::deserialize(&frame), Ok(Message::Hello(expected.clone())));
+
+        // Nodes only send what both of them support
+        let common = Capabilities::local().common(&expected);
+        assert_eq!(common.version, PROTOCOL_VERSION);
+        assert!(common.compression.is_empty());
+        let small = Message::Put(Hash::from(42), Payload(vec![0; 4096]), None);
+        let large = Message::Put(Hash::from(42), Payload(vec![0; 4097]), None);
+        let blake3 = Message::Get(Hash::of_content(Algorithm::Blake3, b""));
+        assert!(common.accepts(&small));
+        assert!(!common.accepts(&large));
+        assert!(!common.accepts(&blake3));
+        assert!(Capabilities::local().accepts(&blake3));
+    }
+
+    #[test]
+    fn parse_hash() {
+        let hash = Hash::of_content(Algorithm::Sha256, b"");
+        let text = format!("{:?}", hash);
+        assert_eq!(
+            text,
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(text.parse(), Ok(hash));
+        assert_eq!("2a".parse(), Ok(Hash::from(42)));
+
+        // Content-addressed hashes need their full digest and a known algorithm
+        assert_eq!("sha256:e3b0".parse::<Hash>(), Err(HashParseError));
+        assert_eq!("md5:2a".parse::<Hash>(), Err(HashParseError));
+        assert_eq!("0123456789abcdef0".parse::<Hash>(), Err(HashParseError));
+    }
+
     #[test]
     fn format_hash() {
         use std::fmt::Write;
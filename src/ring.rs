@@ -0,0 +1,114 @@
+use messages::{Hash, NodeId};
+
+/// How many positions each node takes on the ring
+/// More virtual nodes spread the hashes more evenly between nodes
+pub const VIRTUAL_NODES: u64 = 16;
+
+/// Scramble a 64-bit value (splitmix64 finalizer)
+/// Every node must place the virtual nodes at the same positions, so this has to be
+/// deterministic
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Consistent hashing ring
+/// Each node is placed at several positions on a ring covering the hash space. A hash is owned
+/// by the first nodes found walking the ring clockwise from its position, so adding or removing
+/// a node only moves the hashes next to its positions
+#[derive(Debug)]
+pub struct Ring {
+    /// Positions of the virtual nodes, sorted
+    positions: Vec<(u64, NodeId)>,
+    nodes: usize,
+}
+
+impl Ring {
+    /// Build a ring from a list of nodes
+    pub fn new<I: IntoIterator<Item = NodeId>>(nodes: I) -> Self {
+        let mut ids: Vec<NodeId> = nodes.into_iter().collect();
+        ids.sort_by_key(|id| u64::from(*id));
+        ids.dedup();
+
+        let mut positions: Vec<_> = ids.iter()
+            .flat_map(|&id| {
+                (0..VIRTUAL_NODES).map(move |i| (mix(u64::from(id) ^ mix(i)), id))
+            })
+            .collect();
+        positions.sort_by_key(|&(position, id)| (position, u64::from(id)));
+
+        Ring {
+            positions,
+            nodes: ids.len(),
+        }
+    }
+
+    /// The nodes owning a hash, in order of preference
+    /// Returns at most `count` distinct nodes
+    pub fn owners(&self, hash: &Hash, count: usize) -> Vec<NodeId> {
+        let count = count.min(self.nodes);
+        let key = u64::from(*hash);
+        // The first virtual node at or after the hash position
+        let start = self.positions
+            .iter()
+            .position(|&(position, _)| position >= key)
+            .unwrap_or(0);
+
+        let mut owners = Vec::with_capacity(count);
+        for &(_, id) in self.positions.iter().cycle().skip(start) {
+            if owners.len() >= count {
+                break;
+            }
+            if !owners.contains(&id) {
+                owners.push(id);
+            }
+        }
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+    use messages::Hash;
+
+    #[test]
+    fn owners() {
+        let ring = Ring::new((1..6).map(Hash::from));
+        for key in 0..100 {
+            let owners = ring.owners(&Hash::from((key as u64).wrapping_mul(0x0123_4567_89ab_cdef)), 3);
+            let mut distinct = owners.clone();
+            distinct.sort_by_key(|id| u64::from(*id));
+            distinct.dedup();
+            assert_eq!(distinct.len(), 3);
+        }
+
+        // There can't be more owners than nodes
+        assert_eq!(ring.owners(&Hash::from(42), 10).len(), 5);
+        assert!(Ring::new(vec![]).owners(&Hash::from(42), 3).is_empty());
+    }
+
+    #[test]
+    fn deterministic() {
+        let a = Ring::new((1..6).map(Hash::from));
+        let b = Ring::new((1..6).rev().map(Hash::from));
+        for key in 0..100 {
+            let hash = Hash::from((key as u64).wrapping_mul(0x0123_4567_89ab_cdef));
+            assert_eq!(a.owners(&hash, 3), b.owners(&hash, 3));
+        }
+    }
+
+    #[test]
+    fn consistent() {
+        // Adding a node only changes the owner of a fraction of the hashes
+        let before = Ring::new((1..10).map(Hash::from));
+        let after = Ring::new((1..11).map(Hash::from));
+        let moved = (0..1000)
+            .map(|key| Hash::from((key as u64).wrapping_mul(0x0123_4567_89ab_cdef)))
+            .filter(|hash| before.owners(hash, 1) != after.owners(hash, 1))
+            .count();
+        assert!(moved > 0 && moved < 300, "{} hashes moved", moved);
+    }
+}
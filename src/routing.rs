@@ -0,0 +1,463 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use messages::{Hash, NodeId};
+use ring::Ring;
+
+/// How many contacts a bucket holds
+pub const K: usize = 8;
+
+/// How many nodes are queried at once during a lookup
+pub const ALPHA: usize = 3;
+
+/// Time to live for contacts, in seconds
+static TTL: u64 = 10;
+
+/// How many failed sends in a row make a contact forgotten
+static MAX_FAILURES: u32 = 3;
+
+/// How long a lookup keeps querying nodes, in seconds
+static LOOKUP_TIMEOUT: u64 = 5;
+
+/// How hashes are spread between the nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Partitioning {
+    /// Hashes are stored on the nodes closest to them, found with iterative lookups
+    #[default]
+    Kademlia,
+    /// Hashes are stored on their owners on a consistent hashing ring of the known nodes
+    Ring,
+    /// Hashes are sent to every known node
+    Flood,
+}
+
+impl FromStr for Partitioning {
+    type Err = PartitioningParseError;
+
+    fn from_str(s: &str) -> Result<Partitioning, PartitioningParseError> {
+        match s {
+            "kademlia" => Ok(Partitioning::Kademlia),
+            "ring" => Ok(Partitioning::Ring),
+            "flood" => Ok(Partitioning::Flood),
+            _ => Err(PartitioningParseError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitioningParseError;
+
+impl fmt::Display for PartitioningParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("expected one of kademlia, ring or flood")
+    }
+}
+
+impl Error for PartitioningParseError {}
+
+/// A known node
+#[derive(Debug, Clone)]
+struct Contact {
+    id: NodeId,
+    addr: SocketAddr,
+    /// The last time the node was seen
+    last_seen: Instant,
+    /// How many sends to this node failed since it was last seen
+    failures: u32,
+}
+
+impl Contact {
+    fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Contact {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        self.last_seen.elapsed() > Duration::from_secs(TTL)
+    }
+}
+
+/// Kademlia routing table
+/// Contacts are sorted in buckets by their XOR distance to the local node: bucket `i` holds the
+/// nodes at a distance in `[2^i, 2^(i+1))`, least recently seen first
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Contact>>,
+    /// Addresses of nodes that were discovered, but didn't tell their ID yet
+    pending: HashMap<SocketAddr, Instant>,
+}
+
+impl RoutingTable {
+    /// Create an empty routing table for the given local node
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![Vec::new(); 64],
+            pending: HashMap::new(),
+        }
+    }
+
+    /// The local node ID
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// The bucket a node belongs to, if it isn't the local node
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        match self.id.distance(id) {
+            0 => None,
+            distance => Some(63 - distance.leading_zeros() as usize),
+        }
+    }
+
+    /// Probe a node that just talked to us
+    /// Returns true if the node wasn't known before
+    pub fn probe(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        self.pending.remove(&addr);
+        self.insert(id, addr, true)
+    }
+
+    /// Learn about a node from another one
+    /// Unlike `probe`, this doesn't refresh an already known node
+    pub fn learn(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        self.insert(id, addr, false)
+    }
+
+    fn insert(&mut self, id: NodeId, addr: SocketAddr, seen: bool) -> bool {
+        let index = match self.bucket(&id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(position) = bucket.iter().position(|contact| contact.id == id) {
+            if seen {
+                // Move it at the end of the bucket
+                let mut contact = bucket.remove(position);
+                contact.addr = addr;
+                contact.last_seen = Instant::now();
+                contact.failures = 0;
+                bucket.push(contact);
+            }
+            return false;
+        }
+
+        if bucket.len() >= K {
+            // Kademlia favors old contacts: only replace the least recently seen one if it is gone
+            if bucket[0].is_stale() {
+                bucket.remove(0);
+            } else {
+                return false;
+            }
+        }
+
+        bucket.push(Contact::new(id, addr));
+        true
+    }
+
+    /// Remember the address of a node whose ID is still unknown
+    pub fn discover(&mut self, addr: SocketAddr) {
+        self.pending.insert(addr, Instant::now());
+    }
+
+    /// Report a failed send to a node
+    /// Returns true if the node was forgotten after failing too many times in a row
+    pub fn suspect(&mut self, addr: SocketAddr) -> bool {
+        if self.pending.remove(&addr).is_some() {
+            return true;
+        }
+
+        for bucket in &mut self.buckets {
+            if let Some(position) = bucket.iter().position(|contact| contact.addr == addr) {
+                bucket[position].failures += 1;
+                if bucket[position].failures >= MAX_FAILURES {
+                    bucket.remove(position);
+                    return true;
+                }
+                return false;
+            }
+        }
+
+        false
+    }
+
+    /// Cleanup stale nodes
+    pub fn cleanup(&mut self) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|contact| !contact.is_stale());
+        }
+        let ttl = Duration::from_secs(TTL);
+        self.pending.retain(|_, discovered| discovered.elapsed() <= ttl);
+    }
+
+    /// The known nodes closest to a target, closest first
+    pub fn closest(&self, target: &Hash, count: usize) -> Vec<(NodeId, SocketAddr)> {
+        let mut contacts: Vec<_> = self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter())
+            .map(|contact| (contact.id, contact.addr))
+            .collect();
+        contacts.sort_by_key(|&(id, _)| id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    /// The address of a known node
+    pub fn address(&self, id: &NodeId) -> Option<SocketAddr> {
+        let index = self.bucket(id)?;
+        self.buckets[index]
+            .iter()
+            .find(|contact| contact.id == *id)
+            .map(|contact| contact.addr)
+    }
+
+    /// The addresses of the nodes that should store a hash, out of `replicas` owners
+    /// The local node may be one of the owners, and is left out
+    pub fn owners(
+        &self,
+        partitioning: Partitioning,
+        hash: &Hash,
+        replicas: usize,
+    ) -> Vec<SocketAddr> {
+        match partitioning {
+            Partitioning::Kademlia => {
+                let mut owners = self.closest(hash, replicas);
+                let local = self.id.distance(hash);
+                let farthest = owners.last().map(|&(id, _)| id.distance(hash));
+                if owners.len() == replicas && farthest > Some(local) {
+                    owners.pop();
+                }
+                owners.into_iter().map(|(_, addr)| addr).collect()
+            }
+            Partitioning::Ring => {
+                // The local node is part of the ring, but doesn't need to be sent anything
+                let ids = self.buckets
+                    .iter()
+                    .flat_map(|bucket| bucket.iter().map(|contact| contact.id))
+                    .chain(iter::once(self.id));
+                Ring::new(ids)
+                    .owners(hash, replicas)
+                    .iter()
+                    .filter_map(|id| self.address(id))
+                    .collect()
+            }
+            Partitioning::Flood => self.addresses(),
+        }
+    }
+
+    /// List the addresses of every known node, including the ones which ID is unknown
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter().map(|contact| contact.addr))
+            .chain(self.pending.keys().cloned())
+            .collect()
+    }
+
+    /// Check if an address belongs to a known node, even if its ID is still unknown
+    pub fn knows(&self, addr: &SocketAddr) -> bool {
+        self.pending.contains_key(addr)
+            || self.buckets
+                .iter()
+                .any(|bucket| bucket.iter().any(|contact| contact.addr == *addr))
+    }
+
+    /// How many nodes have a known ID
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A single iterative lookup
+#[derive(Debug)]
+struct Lookup {
+    started: Instant,
+    /// The nodes that were already queried
+    queried: HashSet<SocketAddr>,
+    /// The queried nodes that didn't answer yet
+    awaiting: HashSet<SocketAddr>,
+}
+
+/// Tracks the nodes already queried by iterative lookups
+#[derive(Debug, Default)]
+pub struct Lookups(HashMap<Hash, Lookup>);
+
+impl Lookups {
+    /// Start looking a target up, forgetting any previous lookup for it
+    pub fn start(&mut self, target: Hash) {
+        let lookup = Lookup {
+            started: Instant::now(),
+            queried: HashSet::new(),
+            awaiting: HashSet::new(),
+        };
+        self.0.insert(target, lookup);
+    }
+
+    /// Mark a node as queried for a target
+    pub fn queried(&mut self, target: &Hash, addr: SocketAddr) {
+        if let Some(lookup) = self.0.get_mut(target) {
+            lookup.queried.insert(addr);
+            lookup.awaiting.insert(addr);
+        }
+    }
+
+    /// Take the answer of a node to a lookup
+    /// Returns false if the node wasn't queried for this target, or already answered
+    pub fn answered(&mut self, target: &Hash, addr: &SocketAddr) -> bool {
+        self.0
+            .get_mut(target)
+            .is_some_and(|lookup| lookup.awaiting.remove(addr))
+    }
+
+    /// Pick the next nodes to query: the closest ones that weren't queried yet, among the
+    /// known nodes and the contacts an answer just gave
+    /// The lookup converges once the K closest nodes were all queried
+    pub fn next(
+        &mut self,
+        table: &RoutingTable,
+        target: &Hash,
+        contacts: &[(NodeId, SocketAddr)],
+    ) -> Vec<SocketAddr> {
+        let lookup = match self.0.get_mut(target) {
+            Some(lookup) => lookup,
+            None => return Vec::new(),
+        };
+
+        let mut closest = table.closest(target, K);
+        closest.extend(contacts.iter().filter(|&&(id, _)| id != table.id()));
+        closest.sort_by_key(|&(id, _)| id.distance(target));
+        closest.dedup_by_key(|&mut (_, addr)| addr);
+        let mut next = Vec::new();
+        for (_, addr) in closest.into_iter().take(K) {
+            if next.len() < ALPHA && !lookup.queried.contains(&addr) && !next.contains(&addr) {
+                next.push(addr);
+            }
+        }
+        lookup.queried.extend(next.iter().cloned());
+        lookup.awaiting.extend(next.iter().cloned());
+        next
+    }
+
+    /// Forget the lookups that went on for too long
+    pub fn cleanup(&mut self) {
+        let timeout = Duration::from_secs(LOOKUP_TIMEOUT);
+        self.0.retain(|_, lookup| lookup.started.elapsed() <= timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lookups, Partitioning, RoutingTable, ALPHA, K};
+    use std::net::SocketAddr;
+    use messages::Hash;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn buckets() {
+        let mut table = RoutingTable::new(Hash::from(0));
+
+        // The local node is never stored
+        assert!(!table.probe(Hash::from(0), addr(1)));
+        assert!(table.probe(Hash::from(1), addr(1)));
+        assert!(!table.probe(Hash::from(1), addr(1)));
+        assert_eq!(table.len(), 1);
+
+        // A full bucket keeps its live contacts
+        for i in 0..(K as u64 + 2) {
+            table.probe(Hash::from(0x100 + i), addr(0x100 + i as u16));
+        }
+        assert_eq!(table.len(), 1 + K);
+    }
+
+    #[test]
+    fn closest() {
+        let mut table = RoutingTable::new(Hash::from(0));
+        for i in 1..20 {
+            table.probe(Hash::from(i), addr(i as u16));
+        }
+
+        let closest = table.closest(&Hash::from(0b1000), 3);
+        let ids: Vec<_> = closest.into_iter().map(|(id, _)| u64::from(id)).collect();
+        assert_eq!(ids, vec![0b1000, 0b1001, 0b1010]);
+    }
+
+    #[test]
+    fn owners() {
+        let mut table = RoutingTable::new(Hash::from(0));
+        for i in 1..20 {
+            table.probe(Hash::from(i), addr(i as u16));
+        }
+        table.discover(addr(42));
+        let hash = Hash::from(0b1000);
+
+        assert_eq!(table.owners(Partitioning::Kademlia, &hash, 3).len(), 3);
+        assert_eq!(table.owners(Partitioning::Flood, &hash, 3).len(), 20);
+
+        // The local node may be one of the owners on the ring…
+        let owners = table.owners(Partitioning::Ring, &hash, 3);
+        assert!(owners.len() == 3 || owners.len() == 2);
+
+        // …and is one of them when it is close enough to the hash
+        let owners = table.owners(Partitioning::Kademlia, &Hash::from(0b10), 3);
+        assert_eq!(owners, vec![addr(0b10), addr(0b11)]);
+        assert!(table.knows(&addr(42)));
+        assert!(!table.knows(&addr(43)));
+    }
+
+    #[test]
+    fn suspect() {
+        let mut table = RoutingTable::new(Hash::from(0));
+        table.probe(Hash::from(1), addr(1));
+        table.discover(addr(2));
+        assert_eq!(table.addresses().len(), 2);
+
+        // Pending addresses are dropped at once, known nodes after a few failures
+        assert!(table.suspect(addr(2)));
+        assert!(!table.suspect(addr(1)));
+        assert!(!table.suspect(addr(1)));
+        assert!(table.suspect(addr(1)));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn lookup() {
+        let mut table = RoutingTable::new(Hash::from(0));
+        let target = Hash::from(0xff);
+        for i in 1..5 {
+            table.probe(Hash::from(i), addr(i as u16));
+        }
+
+        let mut lookups = Lookups::default();
+        assert!(lookups.next(&table, &target, &[]).is_empty());
+        lookups.start(target);
+        assert_eq!(lookups.next(&table, &target, &[]).len(), ALPHA);
+
+        // Only the nodes that weren't queried yet are returned, answers included
+        let contacts = [(Hash::from(0xfe), addr(0xfe)), (Hash::from(0), addr(0))];
+        assert_eq!(lookups.next(&table, &target, &contacts), vec![addr(0xfe), addr(1)]);
+        assert!(lookups.next(&table, &target, &[]).is_empty());
+
+        // Each queried node answers once
+        assert!(lookups.answered(&target, &addr(0xfe)));
+        assert!(!lookups.answered(&target, &addr(0xfe)));
+        assert!(!lookups.answered(&target, &addr(0xfd)));
+        assert!(!lookups.answered(&Hash::from(0xfe), &addr(1)));
+    }
+}
@@ -1,83 +1,554 @@
-use std::net::SocketAddr;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use std::cell::RefCell;
-use futures::{Future, IntoFuture, Sink, Stream};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+use futures::{future, stream, Async, AsyncSink, Future, IntoFuture, Poll, Sink, StartSend, Stream};
 use futures::sync::mpsc;
-use tokio_core::net::UdpSocket;
+use net2::TcpBuilder;
+use tokio_core::net::{TcpListener, TcpStream, UdpSocket};
 use tokio_core::reactor::Handle;
+use tokio_io::{io as async_io, AsyncRead, AsyncWrite};
+use tokio_timer::Timer;
+use tokio_uds::UnixListener;
 
-use messages::{Message, UdpMessage};
-use state::State;
+use messages::{
+    Capabilities, DecodeError, ErrorCode, Message, Payload, RequestId, TcpMessage, MAX_FRAME,
+};
+use routing::{Lookups, Partitioning, RoutingTable, K};
+use state::{Origin, State};
 
-static TTL: u64 = 10;
+/// Stream of messages received on a UDP socket, with the ID of the request they belong to
+/// Datagrams that can't be decoded are yielded as errors along with their source
+struct Incoming {
+    socket: Arc<UdpSocket>,
+    buffer: Vec<u8>,
+}
 
-/// Store a list of known peers
-#[derive(Debug, Default)]
-struct PeerStore {
-    peers: RefCell<HashMap<SocketAddr, Peer>>,
+impl Incoming {
+    fn new(socket: Arc<UdpSocket>) -> Self {
+        Incoming {
+            socket,
+            buffer: vec![0; 64 * 1024],
+        }
+    }
 }
 
-impl PeerStore {
-    /// Probe a peer
-    /// Returns true if the peer wasn't known before
-    pub fn probe(&self, addr: SocketAddr) -> bool {
-        let is_new = !self.peers.borrow().contains_key(&addr);
-        self.peers
-            .borrow_mut()
-            .entry(addr)
-            .or_insert_with(Peer::new)
-            .probe();
-        is_new
+impl Stream for Incoming {
+    type Item = Received;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, src)) => {
+                    let msg = Message::deserialize_request(&self.buffer[..len]);
+                    return Ok(Async::Ready(Some((src, msg))));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                // Some platforms report here that a previous destination was unreachable
+                Err(e) => warn!("Error receiving message: {}", e),
+            }
+        }
     }
+}
 
-    /// Probe a peer, and print if it was just discovered
-    pub fn probe_and_announce(&self, addr: SocketAddr) {
-        if self.probe(addr) {
-            println!("Discovered new peer. Hi {}!", addr);
+/// A message and the ID of the request it belongs to, if it could be decoded
+pub type Decoded = Result<(Option<RequestId>, Message), DecodeError>;
+
+/// A message received from an address, if it could be decoded
+type Received = (SocketAddr, Decoded);
+
+/// How messages are carried between nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// One message per datagram
+    #[default]
+    Udp,
+    /// Length-prefixed messages on connections, for lossy and firewalled networks
+    /// Messages have the same size limits as over UDP
+    Tcp,
+}
+
+impl FromStr for Protocol {
+    type Err = ProtocolParseError;
+
+    fn from_str(s: &str) -> Result<Protocol, ProtocolParseError> {
+        match s {
+            "udp" => Ok(Protocol::Udp),
+            "tcp" => Ok(Protocol::Tcp),
+            _ => Err(ProtocolParseError),
         }
     }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolParseError;
+
+impl fmt::Display for ProtocolParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("expected either udp or tcp")
+    }
+}
+
+impl Error for ProtocolParseError {}
+
+/// Sends encoded messages to addresses
+trait Transport {
+    /// Send a frame, failing with WouldBlock if it must be sent again later
+    fn send_frame(&self, frame: &[u8], addr: &SocketAddr) -> io::Result<()>;
+
+    /// Check if messages come through connections, whose source can't be spoofed
+    fn is_connected(&self) -> bool;
+}
 
-    /// Cleanup stale peers
-    pub fn cleanup(&self) {
-        self.peers.borrow_mut().retain(|_, peer| !peer.is_stale());
+impl Transport for Arc<UdpSocket> {
+    fn send_frame(&self, frame: &[u8], addr: &SocketAddr) -> io::Result<()> {
+        self.send_to(frame, addr).map(|_| ())
     }
 
-    /// List known peers addresses
-    pub fn addresses(&self) -> Vec<SocketAddr> {
-        self.peers
-            .borrow()
-            .keys()
-            .map(|addr| addr.clone())
-            .collect()
+    fn is_connected(&self) -> bool {
+        false
     }
+}
+
+/// Starts the first frame of the connections a node opens, followed by the port it listens to
+static LISTENING: &[u8] = b"DHTPORT";
+
+/// How long an accepted connection has to send its first frame, in seconds
+static FIRST_FRAME_TIMEOUT: u64 = 10;
+
+/// How many frames wait to be written on a connection
+/// Connections too slow to keep up are closed, rather than queuing frames without end
+static MAX_PENDING_FRAMES: usize = 256;
 
-    pub fn len(&self) -> usize {
-        self.peers.borrow().len()
+/// A TCP socket bound to an address
+fn tcp_socket(addr: &SocketAddr) -> io::Result<TcpBuilder> {
+    let socket = if addr.is_ipv4() {
+        TcpBuilder::new_v4()?
+    } else {
+        TcpBuilder::new_v6()?
+    };
+    socket.reuse_address(true)?.bind(addr)?;
+    Ok(socket)
+}
+
+/// The port a node listens to, if a frame announces it
+fn listening_port(frame: &[u8]) -> Option<u16> {
+    match frame.strip_prefix(LISTENING) {
+        Some(&[high, low]) => Some(u16::from_be_bytes([high, low])),
+        _ => None,
     }
 }
 
-/// A single peer
-#[derive(Debug, Clone)]
-struct Peer {
-    /// The last time the peer was seen
-    last_seen: Instant,
+/// The connection written to for a key
+struct Writer<K> {
+    /// The ID of the connection
+    id: usize,
+    /// Frames waiting to be written on it
+    frames: mpsc::Sender<Vec<u8>>,
+    /// The key its messages are read under, which changes when it takes over the key it claims
+    key: Rc<Cell<K>>,
 }
 
-impl Peer {
-    fn new() -> Self {
-        Peer {
-            last_seen: Instant::now(),
+/// The connections of a listener, and the messages read from them
+/// Each connection is known by a key, the address of the other end for TCP
+struct Connections<K> {
+    handle: Handle,
+    /// The connection written to for each key
+    writers: Rc<RefCell<HashMap<K, Writer<K>>>>,
+    /// The connection waiting to take over a key once the one that has it is closed, with its
+    /// own key and ID
+    claims: Rc<RefCell<HashMap<K, (K, usize)>>>,
+    /// The ID of the last connection opened
+    last_id: Rc<Cell<usize>>,
+    /// Messages read from every connection
+    incoming: mpsc::UnboundedSender<(K, Decoded)>,
+}
+
+impl<K> Clone for Connections<K> {
+    fn clone(&self) -> Self {
+        Connections {
+            handle: self.handle.clone(),
+            writers: Rc::clone(&self.writers),
+            claims: Rc::clone(&self.claims),
+            last_id: Rc::clone(&self.last_id),
+            incoming: self.incoming.clone(),
         }
     }
+}
+
+impl<K: Copy + Eq + Hash + fmt::Display + 'static> Connections<K> {
+    /// No connections yet, and the stream of the messages they will read
+    fn new(handle: &Handle) -> (Self, mpsc::UnboundedReceiver<(K, Decoded)>) {
+        let (incoming, messages) = mpsc::unbounded();
+        let connections = Connections {
+            handle: handle.clone(),
+            writers: Rc::default(),
+            claims: Rc::default(),
+            last_id: Rc::default(),
+            incoming,
+        };
+        (connections, messages)
+    }
+
+    /// Read messages from a connection into the incoming ones, under its current key, and write
+    /// the frames sent to it
+    /// The returned future resolves when the connection is closed
+    fn serve<S, F>(
+        &self,
+        key: Rc<Cell<K>>,
+        stream: F,
+        frames: mpsc::Receiver<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = ()>>
+    where
+        S: AsyncRead + AsyncWrite + 'static,
+        F: Future<Item = S, Error = io::Error> + 'static,
+    {
+        let incoming = self.incoming.clone();
+        let closed = Rc::clone(&key);
+        Box::new(
+            stream
+                .and_then(move |stream| {
+                    let (sink, stream) = stream.framed(TcpMessage).split();
+                    let incoming = incoming.sink_map_err(|_| io::Error::other("listener closed"));
+                    let read = stream.map(move |msg| (key.get(), msg)).forward(incoming);
+                    let write = frames
+                        .map_err(|_| io::Error::other("listener closed"))
+                        .forward(sink);
+                    // Whichever way the connection ends, it is of no use anymore
+                    read.map(|_| ()).select(write.map(|_| ())).map_err(|(e, _)| e)
+                })
+                .map(|_| ())
+                .map_err(move |e| warn!("Connection {} closed: {}", closed.get(), e)),
+        )
+    }
+
+    /// Serve a connection, writing the frames sent to its key on it instead of on the previous
+    /// connection with that key
+    /// Once it is closed, the connection is forgotten unless another one replaced it, and the
+    /// connection claiming its key takes it over. Returns the ID of the connection
+    fn open<S, F>(&self, key: K, stream: F) -> usize
+    where
+        S: AsyncRead + AsyncWrite + 'static,
+        F: Future<Item = S, Error = io::Error> + 'static,
+    {
+        let (writer, frames) = mpsc::channel(MAX_PENDING_FRAMES);
+        let id = self.last_id.get() + 1;
+        self.last_id.set(id);
+        let key = Rc::new(Cell::new(key));
+        let connection = self.serve(Rc::clone(&key), stream, frames);
+        let writer = Writer {
+            id,
+            frames: writer,
+            key: Rc::clone(&key),
+        };
+        self.writers.borrow_mut().insert(key.get(), writer);
+
+        let writers = Rc::clone(&self.writers);
+        let claims = Rc::clone(&self.claims);
+        self.handle.spawn(connection.then(move |_| {
+            let key = key.get();
+            let mut writers = writers.borrow_mut();
+            let mut claims = claims.borrow_mut();
+            claims.retain(|_, &mut (_, claimer)| claimer != id);
+            // The key may have been dropped already, if the connection was too slow
+            if writers.get(&key).is_none_or(|writer| writer.id == id) {
+                writers.remove(&key);
+                if let Some((claimer, claimer_id)) = claims.remove(&key) {
+                    if writers.get(&claimer).is_some_and(|writer| writer.id == claimer_id) {
+                        debug!("Connection {} takes {} over", claimer, key);
+                        let writer = writers.remove(&claimer).unwrap();
+                        writer.key.set(key);
+                        writers.insert(key, writer);
+                    }
+                }
+            }
+            Ok(())
+        }));
+        id
+    }
 
-    fn probe(&mut self) {
-        self.last_seen = Instant::now();
+    /// Serve a connection accepted by a listener
+    fn accept<S: AsyncRead + AsyncWrite + 'static>(&self, key: K, stream: S) {
+        self.open(key, future::ok(stream));
     }
 
-    fn is_stale(&self) -> bool {
-        self.last_seen.elapsed() > Duration::from_secs(TTL)
+    /// Serve a connection accepted by a listener under its own key, while it claims a key that
+    /// another connection has
+    /// It takes the claimed key over once the other connection is closed
+    fn claim<S: AsyncRead + AsyncWrite + 'static>(&self, claimed: K, key: K, stream: S) {
+        let id = self.open(key, future::ok(stream));
+        self.claims.borrow_mut().insert(claimed, (key, id));
+    }
+
+    /// Check if frames sent to a key are written on a connection
+    fn is_open(&self, key: &K) -> bool {
+        self.writers.borrow().contains_key(key)
+    }
+
+    /// Write a frame on a connection
+    /// A connection with too many frames waiting is closed, once the ones already queued are
+    /// written, and the frame is dropped
+    fn send_frame(&self, frame: &[u8], key: &K) -> io::Result<()> {
+        let mut writers = self.writers.borrow_mut();
+        let sent = match writers.get_mut(key) {
+            Some(writer) => writer.frames.try_send(frame.to_vec()),
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed")),
+        };
+        match sent {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.is_full() => {
+                warn!("Connection {} too slow, closing it", key);
+                writers.remove(key);
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "too many frames waiting"))
+            }
+            Err(_) => {
+                writers.remove(key);
+                Err(io::Error::new(io::ErrorKind::NotConnected, "connection closed"))
+            }
+        }
+    }
+}
+
+/// The TCP connections of a listener, by address of the other end
+/// Connections to other nodes start with the port this node listens to, so that both ends know
+/// each other by the address they listen to, as they would over UDP
+#[derive(Clone)]
+struct TcpConnections {
+    local: SocketAddr,
+    connections: Connections<SocketAddr>,
+    /// Bounds the wait for the first frame of accepted connections
+    timer: Timer,
+}
+
+impl TcpConnections {
+    /// Connect to a node
+    /// Once closed, the connection is forgotten, and the next message to the node opens another
+    fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        // From an ephemeral port: sharing the one of the listener would let others share it too
+        let socket = tcp_socket(&SocketAddr::new(self.local.ip(), 0))?.to_tcp_stream()?;
+        // A socket bound to an IPv6 address reaches IPv4 ones through their mapped address
+        let target = match (self.local, addr) {
+            (SocketAddr::V6(_), SocketAddr::V4(v4)) => {
+                SocketAddr::from(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+            }
+            _ => addr,
+        };
+        let stream = TcpStream::connect_stream(socket, &target, &self.connections.handle);
+        self.connections.open(addr, stream);
+
+        let mut frame = LISTENING.to_vec();
+        frame.extend_from_slice(&self.local.port().to_be_bytes());
+        self.connections.send_frame(&frame, &addr)
+    }
+
+    /// Serve a connection accepted by the listener
+    /// Its first frame tells if it comes from a node, known by the address it listens to, or
+    /// is the first message of a client. Anyone can claim to listen to a port: a connection
+    /// claiming the address of an open one keeps its own address until that one is closed, which
+    /// the KeepAlive messages sent every second on it tell if the other end is gone
+    fn accept(
+        &self,
+        addr: SocketAddr,
+        stream: TcpStream,
+    ) -> Box<dyn Future<Item = (), Error = ()>> {
+        let connections = self.connections.clone();
+        let first = async_io::read_exact(stream, [0; 4]).and_then(|(stream, len)| {
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_FRAME {
+                return future::Either::A(future::err(DecodeError::MessageTooLong.into()));
+            }
+            future::Either::B(async_io::read_exact(stream, vec![0; len]))
+        });
+        let first = self.timer.timeout(first, Duration::from_secs(FIRST_FRAME_TIMEOUT));
+        Box::new(
+            first
+                .map(move |(stream, frame)| match listening_port(&frame) {
+                    Some(port) => {
+                        let node = SocketAddr::new(addr.ip(), port);
+                        if connections.is_open(&node) {
+                            debug!("Connection {} claims {}, which is already open", addr, node);
+                            connections.claim(node, addr, stream);
+                        } else {
+                            connections.accept(node, stream);
+                        }
+                    }
+                    None => {
+                        connections.accept(addr, stream);
+                        let msg = Message::deserialize_request(&frame);
+                        let _ = connections.incoming.unbounded_send((addr, msg));
+                    }
+                })
+                .map_err(move |e| debug!("Connection {} closed: {}", addr, e)),
+        )
+    }
+}
+
+impl Transport for TcpConnections {
+    fn send_frame(&self, frame: &[u8], addr: &SocketAddr) -> io::Result<()> {
+        if !self.connections.is_open(addr) {
+            self.connect(*addr)?;
+        }
+        self.connections.send_frame(frame, addr)
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+/// The capabilities shared with the nodes that said hello
+#[derive(Debug, Default)]
+struct Peers(RefCell<HashMap<SocketAddr, Capabilities>>);
+
+impl Peers {
+    /// Remember what a node supports
+    /// Returns true if it wasn't known before, or changed
+    fn hello(&self, addr: SocketAddr, capabilities: &Capabilities) -> bool {
+        let common = Capabilities::local().common(capabilities);
+        self.0.borrow_mut().insert(addr, common.clone()) != Some(common)
+    }
+
+    /// What this node and another one both support
+    fn get(&self, addr: &SocketAddr) -> Option<Capabilities> {
+        self.0.borrow().get(addr).cloned()
+    }
+
+    fn forget(&self, addr: &SocketAddr) {
+        self.0.borrow_mut().remove(addr);
+    }
+
+    /// Forget the nodes that are not known anymore
+    fn retain<F: Fn(&SocketAddr) -> bool>(&self, known: F) {
+        self.0.borrow_mut().retain(|addr, _| known(addr));
+    }
+}
+
+/// Sink sending messages through a transport
+/// A failed send only drops the message being sent, and reports its destination
+struct Outgoing<T, F> {
+    transport: T,
+    /// Nodes that said hello get messages in their version, the others in the legacy framing
+    peers: Rc<Peers>,
+    /// The encoded message being sent
+    pending: Option<(SocketAddr, Vec<u8>)>,
+    on_error: F,
+}
+
+impl<T: Transport, F: FnMut(SocketAddr, io::Error)> Outgoing<T, F> {
+    fn new(transport: T, peers: Rc<Peers>, on_error: F) -> Self {
+        Outgoing {
+            transport,
+            peers,
+            pending: None,
+            on_error,
+        }
+    }
+}
+
+impl<T: Transport, F: FnMut(SocketAddr, io::Error)> Sink for Outgoing<T, F> {
+    type SinkItem = (SocketAddr, Option<RequestId>, Message);
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, ()> {
+        if let Async::NotReady = self.poll_complete()? {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        let (addr, request, msg) = item;
+        let frame = match self.peers.get(&addr) {
+            Some(ref capabilities) if !capabilities.accepts(&msg) => {
+                debug!("Not sending {:?} to {}, which does not support it", msg, addr);
+                return Ok(AsyncSink::Ready);
+            }
+            Some(capabilities) => msg.serialize_for(capabilities.version, request),
+            None => msg.serialize_for(0, request),
+        };
+        match frame {
+            Ok(buf) => self.pending = Some((addr, buf)),
+            // This node built the message, so there is no reason to blame the destination
+            Err(e) => error!("Could not encode message to {}: {}", addr, e),
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), ()> {
+        if let Some((addr, buf)) = self.pending.take() {
+            match self.transport.send_frame(&buf, &addr) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.pending = Some((addr, buf));
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => (self.on_error)(addr, e),
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Queues messages to be sent by a listener
+#[derive(Clone)]
+struct Outbox<'a> {
+    handle: &'a Handle,
+    sender: mpsc::Sender<(SocketAddr, Option<RequestId>, Message)>,
+}
+
+impl<'a> Outbox<'a> {
+    fn send(&self, addr: SocketAddr, msg: Message) {
+        self.reply(addr, None, msg);
+    }
+
+    /// Send the answer to a request, carrying its ID
+    fn reply(&self, addr: SocketAddr, request: Option<RequestId>, msg: Message) {
+        self.handle.spawn(
+            self.sender
+                .clone()
+                .send((addr, request, msg))
+                .map_err(|e| error!("Error sending message: {}", e))
+                .map(|_| ()),
+        );
+    }
+}
+
+/// Listener options
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Answer datagrams that can't be decoded with an ERROR message
+    pub reply_errors: bool,
+    /// How hashes are spread between the nodes
+    pub partitioning: Partitioning,
+}
+
+/// How many sources of invalid messages are counted at once
+static MAX_INVALID_SOURCES: usize = 1024;
+
+/// Counts invalid messages received from each source
+#[derive(Debug, Default)]
+struct InvalidSources(RefCell<HashMap<SocketAddr, u64>>);
+
+impl InvalidSources {
+    /// Count an invalid message from a source, returning how many were received from it so far
+    /// The counts start over once too many sources sent invalid messages
+    pub fn count(&self, addr: SocketAddr) -> u64 {
+        let mut sources = self.0.borrow_mut();
+        if sources.len() >= MAX_INVALID_SOURCES && !sources.contains_key(&addr) {
+            sources.clear();
+        }
+        let count = sources.entry(addr).or_insert(0);
+        *count += 1;
+        *count
     }
 }
 
@@ -85,75 +556,815 @@ impl Peer {
 /// Multiple servers sharing the same state can listen at the same time
 pub fn listen<'a>(
     state: &'a State,
+    protocol: Protocol,
     addr: &SocketAddr,
+    config: &Config,
     handle: &'a Handle,
-) -> Box<Future<Item = (), Error = ()> + 'a> {
-    // Bind the socket
-    let socket = UdpSocket::bind(&addr.clone(), handle).expect("Could not bind socket");
-    println!("Listening on {}", socket.local_addr().unwrap());
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    match protocol {
+        Protocol::Udp => {
+            // Bind the socket
+            let socket = UdpSocket::bind(&addr.clone(), handle).expect("Could not bind socket");
+            println!("Listening on udp://{}", socket.local_addr().unwrap());
+            serve(state, socket, config, handle)
+        }
+        Protocol::Tcp => {
+            // The port is shared with the connections to the other nodes
+            let listener = tcp_socket(addr)
+                .and_then(|socket| socket.listen(128))
+                .and_then(|listener| TcpListener::from_listener(listener, addr, handle))
+                .expect("Could not bind socket");
+            println!("Listening on tcp://{}", listener.local_addr().unwrap());
+            serve_tcp(state, listener, config, handle)
+        }
+    }
+}
 
-    // Create a framed Stream/Sink, that encodes and decodes messages
-    let (output_sink, input_stream) = socket.framed(UdpMessage).split();
-    let output_sink = output_sink.sink_map_err(|e| error!("Error sending message: {}", e));
+/// Serve requests coming on an already bound socket
+pub fn serve<'a>(
+    state: &'a State,
+    socket: UdpSocket,
+    config: &Config,
+    handle: &'a Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    let socket = Arc::new(socket);
+    let input_stream = Incoming::new(Arc::clone(&socket));
+    run(state, input_stream, socket, config, handle)
+}
+
+/// Serve requests coming on the connections to an already bound listener
+pub fn serve_tcp<'a>(
+    state: &'a State,
+    listener: TcpListener,
+    config: &Config,
+    handle: &'a Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    let (connections, input_stream) = Connections::new(handle);
+    let connections = TcpConnections {
+        local: listener.local_addr().unwrap(),
+        connections,
+        timer: Timer::default(),
+    };
+
+    let accepting = connections.clone();
+    let accept_future = listener
+        .incoming()
+        .for_each(move |(stream, addr)| {
+            handle.spawn(accepting.accept(addr, stream));
+            Ok(())
+        })
+        .map_err(|e| error!("Error accepting connection: {}", e));
+    let server_future = run(state, input_stream, connections, config, handle);
+    Box::new(accept_future.join(server_future).map(|_| ()))
+}
+
+/// Listen to local clients on a Unix socket
+/// A socket left over by a previous server is replaced
+pub fn listen_unix<'a>(
+    state: &'a State,
+    path: &Path,
+    config: &Config,
+    handle: &'a Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if stale {
+        fs::remove_file(path).expect("Could not remove stale socket");
+    }
+    let listener = UnixListener::bind(path, handle).expect("Could not bind socket");
+    println!("Listening on {}", path.display());
+    serve_unix(state, listener, config, handle)
+}
+
+/// Serve the clients connecting to an already bound Unix socket
+/// They are local processes rather than parts of the network, each told apart by its connection,
+/// and messages to them never get lost as they may on loopback UDP
+pub fn serve_unix<'a>(
+    state: &'a State,
+    listener: UnixListener,
+    config: &Config,
+    handle: &'a Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a> {
+    let (connections, input_stream) = Connections::new(handle);
+
+    // Connections are numbered as they come
+    let accepting = connections.clone();
+    let mut last_id: usize = 0;
+    let accept_future = listener
+        .incoming()
+        .for_each(move |(stream, _)| {
+            last_id += 1;
+            accepting.accept(last_id, stream);
+            Ok(())
+        })
+        .map_err(|e| error!("Error accepting connection: {}", e));
+
+    let reply_errors = config.reply_errors;
+    let server_future = input_stream.for_each(move |(id, msg)| {
+        let responses = match msg {
+            Ok((request, msg)) => state.process(msg, request, Origin::Socket(id)),
+            Err(e) => {
+                warn!("Invalid message from local client {}: {}", id, e);
+                if !reply_errors {
+                    return Ok(());
+                }
+                let error = Message::Error(ErrorCode::InvalidMessage, e.to_string());
+                Box::new(stream::once(Ok((None, error))))
+            }
+        };
+
+        // Answer on the connection the request came from, while it is open
+        let connections = connections.clone();
+        handle.spawn(responses.for_each(move |(request, msg)| {
+            let frame = msg
+                .serialize_for(0, request)
+                .map_err(|e| error!("Could not encode message to local client {}: {}", id, e))?;
+            connections
+                .send_frame(&frame, &id)
+                .map_err(|e| debug!("Could not answer local client {}: {}", id, e))
+        }));
+        Ok(())
+    });
+    Box::new(accept_future.join(server_future).map(|_| ()))
+}
+
+/// Serve the messages coming from a transport, and send the answers through it
+fn run<'a, I, T>(
+    state: &'a State,
+    input_stream: I,
+    transport: T,
+    config: &Config,
+    handle: &'a Handle,
+) -> Box<dyn Future<Item = (), Error = ()> + 'a>
+where
+    I: Stream<Item = Received, Error = ()> + 'a,
+    T: Transport + 'a,
+{
+    // The known nodes, the lookups going on, and what the nodes support
+    let shared_table = Rc::new(RefCell::new(RoutingTable::new(state.id())));
+    let shared_lookups: Rc<RefCell<Lookups>> = Rc::default();
+    let shared_peers: Rc<Peers> = Rc::default();
+    state.add_routing_table(Rc::clone(&shared_table));
+
+    // Create a Sink that encodes messages
+    let connected = transport.is_connected();
+    let table = Rc::clone(&shared_table);
+    let peers = Rc::clone(&shared_peers);
+    let output_sink = Outgoing::new(transport, Rc::clone(&shared_peers), move |addr, e| {
+        // Keep sending the other messages, but stop trusting this node
+        error!("Error sending message to {}: {}", addr, e);
+        if table.borrow_mut().suspect(addr) {
+            println!("Peer {} is unreachable. Bye!", addr);
+            peers.forget(&addr);
+        }
+    });
 
     // Channel through which the messages are output
     let (sender, receiver) = mpsc::channel(10);
     let send_future = receiver.forward(output_sink);
+    let outbox = Outbox { handle, sender };
 
-    // The known peer list
-    let shared_peers: Arc<PeerStore> = Arc::default();
-
-    let peers = Arc::clone(&shared_peers);
-    let br_sender = sender.clone();
+    let table = Rc::clone(&shared_table);
+    let lookups = Rc::clone(&shared_lookups);
+    let peers = Rc::clone(&shared_peers);
+    let br_outbox = outbox.clone();
+    let partitioning = config.partitioning;
+    let replicas = state.replication().replicas;
     let broadcast_future = state.subscribe().for_each(move |msg| {
-        // Broadcast messages to every known peers
-        peers.cleanup(); // Cleanup stale peers before
+        let mut table = table.borrow_mut();
+        let mut lookups = lookups.borrow_mut();
+        // Cleanup stale nodes, lookups and capabilities before
+        table.cleanup();
+        lookups.cleanup();
+        peers.retain(|addr| table.knows(addr));
 
-        // If it is a DISCOVER message, do not broadcast and add peer to known peer list
-        if let Message::Discover(addr) = msg {
-            peers.probe_and_announce(addr);
-            return Ok(());
-        }
+        match msg {
+            // If it is a DISCOVER message, do not broadcast: contact the new node, tell it what
+            // this node supports, and look our own ID up through it to fill the routing table
+            Message::Discover(addr) => {
+                let id = table.id();
+                table.discover(addr);
+                lookups.start(id);
+                lookups.queried(&id, addr);
+                br_outbox.send(addr, Message::KeepAlive(id));
+                br_outbox.send(addr, Message::Hello(Capabilities::local()));
+                br_outbox.send(addr, Message::FindNode(id));
+            }
+            // Look missing hashes up, starting with the closest nodes
+            Message::Get(hash) if partitioning == Partitioning::Kademlia => {
+                lookups.start(hash);
+                for addr in lookups.next(&table, &hash, &[]) {
+                    br_outbox.send(addr, Message::FindValue(hash));
+                }
+            }
+            // Only the nodes owning a hash should store it, delete it, or be asked for it
+            Message::Get(hash)
+            | Message::IHave(hash)
+            | Message::Put(hash, _, _)
+            | Message::Delete(hash) => {
+                for addr in table.owners(partitioning, &hash, replicas) {
+                    br_outbox.send(addr, msg.clone());
+                }
+            }
+            // Broadcast anything else to every known node
+            msg => {
+                if !table.is_empty() {
+                    debug!("Broadcasting {:?} to {:?}", msg, table);
+                }
 
-        if peers.len() > 0 {
-            debug!("Broadcasting {:?} to {:?}", msg, peers);
+                for addr in table.addresses() {
+                    br_outbox.send(addr, msg.clone());
+                }
+            }
         }
 
-        for address in peers.addresses() {
-            // Broadcast the message to everyone
-            handle.spawn(
-                br_sender
-                    .clone()
-                    .send((address, msg.clone()))
-                    .map_err(|e| error!("Error broadcasting message: {}", e))
-                    .map(|_| ()),
-            );
+        Ok(())
+    });
+
+    let table = Rc::clone(&shared_table);
+    let lookups = Rc::clone(&shared_lookups);
+    let peers = shared_peers;
+    let invalid_sources = InvalidSources::default();
+    let reply_errors = config.reply_errors;
+    let server_future = input_stream.for_each(move |(src, msg)| {
+        let (request, msg) = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                // Skip invalid messages, and keep serving the others
+                let count = invalid_sources.count(src);
+                warn!("Invalid message from {} ({} so far): {}", src, count, e);
+                if reply_errors {
+                    let error = Message::Error(ErrorCode::InvalidMessage, e.to_string());
+                    outbox.send(src, error);
+                }
+                return Ok(());
+            }
+        };
+
+        debug!("Got message from {}: {:?}", src, msg);
+
+        // Routing messages are handled here, since the routing table belongs to the listener
+        match msg {
+            Message::KeepAlive(id) => {
+                let is_new = table.borrow_mut().probe(id, src);
+                if is_new {
+                    println!("Discovered new peer. Hi {}!", src);
+                    if peers.get(&src).is_none() {
+                        outbox.send(src, Message::Hello(Capabilities::local()));
+                    }
+                }
+            }
+            // Answer nodes that didn't know what this one supports yet
+            // Anyone can claim to be anyone over UDP, only the known nodes are answered
+            Message::Hello(_) if !table.borrow().knows(&src) => {
+                debug!("Ignoring HELLO from unknown node {}", src);
+                return Ok(());
+            }
+            Message::Hello(capabilities) => {
+                if peers.hello(src, &capabilities) {
+                    info!("Peer {} speaks version {}", src, capabilities.version);
+                    outbox.send(src, Message::Hello(Capabilities::local()));
+                }
+                return Ok(());
+            }
+            Message::FindNode(target) => {
+                let nodes = table.borrow().closest(&target, K);
+                outbox.reply(src, request, Message::Nodes(target, nodes));
+                return Ok(());
+            }
+            Message::FindValue(hash) => {
+                let response = match state.get(&hash) {
+                    Some(data) => Message::Put(hash, Payload(data), state.ttl(&hash)),
+                    None => Message::Nodes(hash, table.borrow().closest(&hash, K)),
+                };
+                outbox.reply(src, request, response);
+                return Ok(());
+            }
+            // Notifications would go to whoever the source of a datagram claims to be
+            Message::Watch(_) if !connected => {
+                let reason = String::from("watching needs a connection");
+                outbox.reply(src, request, Message::Error(ErrorCode::InvalidRequest, reason));
+                return Ok(());
+            }
+            // Only the nodes queried by a lookup are listened to, once per query
+            Message::Nodes(target, _) if !lookups.borrow_mut().answered(&target, &src) => {
+                debug!("Ignoring unexpected NODES from {}", src);
+                return Ok(());
+            }
+            Message::Nodes(target, nodes) => {
+                // Keep querying closer nodes while the lookup is useful
+                let table = table.borrow();
+                let query = if target == table.id() {
+                    Message::FindNode(target)
+                } else if state.is_pending(&target) {
+                    Message::FindValue(target)
+                } else {
+                    return Ok(());
+                };
+
+                // Unknown contacts are told about this node, so that what it forwards isn't
+                // taken for a client, and only learned once they send their own KeepAlive
+                for addr in lookups.borrow_mut().next(&table, &target, &nodes) {
+                    if !table.knows(&addr) {
+                        outbox.send(addr, Message::KeepAlive(table.id()));
+                    }
+                    outbox.send(addr, query.clone());
+                }
+                return Ok(());
+            }
+            _ => (),
         }
 
+        // Process incoming messages, telling the nodes from the clients
+        let origin = if table.borrow().knows(&src) {
+            Origin::Peer(src)
+        } else {
+            Origin::Client(src)
+        };
+        let response = state
+            .process(msg, request, origin)
+            .map(move |(request, msg)| (src, request, msg));
+        // send the response to the source
+        let f = outbox
+            .sender
+            .clone()
+            .sink_map_err(|e| error!("Error sending message: {}", e))
+            .send_all(response);
+        handle.spawn(f.map(|_| ()));
         Ok(())
     });
 
-    let peers = Arc::clone(&shared_peers);
-    let server_future = input_stream
-        .for_each(move |(src, msg)| {
-            // Process incoming messages
-            debug!("Got message from {}: {:?}", src, msg);
-            peers.probe_and_announce(src);
-            let response = state.process(msg).map(move |msg| (src, msg));
-            // send the response to the source
-            let f = sender
-                .clone()
-                .sink_map_err(|e| error!("Error sending message: {}", e))
-                .send_all(response);
-            handle.spawn(f.map(|_| ()));
-            Ok(())
-        })
-        .map_err(|e| error!("Error processing message: {}", e));
-
     // Run all three futures
-    return Box::new(
+    Box::new(
         (send_future, server_future, broadcast_future)
             .into_future()
             .map(|_| ()),
-    );
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listening_port, serve, serve_tcp, serve_unix, Config, InvalidSources, LISTENING};
+    use super::{Connections, MAX_INVALID_SOURCES, MAX_PENDING_FRAMES};
+    use std::env;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net;
+    use std::os::unix;
+    use std::process;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+    use futures::{future, Future};
+    use futures::future::Either;
+    use futures::sync::oneshot;
+    use tokio_core::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio_core::reactor::Core;
+    use tokio_uds::UnixListener;
+
+    use messages::{Algorithm, Capabilities, ErrorCode, Hash, Message, Payload, PROTOCOL_VERSION};
+    use state::State;
+
+    /// Run a listener, and a blocking client against it in another thread
+    /// Fails if the listener stops before the client is done
+    fn with_server<F>(config: Config, client: F)
+    where
+        F: FnOnce(net::UdpSocket, net::SocketAddr) + Send + 'static,
+    {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let state = State::default();
+        let socket = UdpSocket::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let server = serve(&state, socket, &config, &handle);
+
+        run_client(&mut core, server, move || {
+            let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client(socket, server_addr);
+        });
+    }
+
+    /// Run a blocking client in another thread until it is done
+    fn run_client<'a, S, F>(core: &mut Core, server: S, client: F)
+    where
+        S: Future<Item = (), Error = ()> + 'a,
+        F: FnOnce() + Send + 'static,
+    {
+        let (done, finished) = oneshot::channel();
+        thread::spawn(move || {
+            client();
+            done.send(()).unwrap();
+        });
+
+        let result = core.run(server.select2(finished));
+        match result {
+            Ok(Either::B((_, _))) => (),
+            _ => panic!("The server or the client stopped"),
+        }
+    }
+
+    /// Wait for a message on a blocking socket, skipping the ones that don't match
+    fn expect<F: Fn(&Message) -> bool>(socket: &net::UdpSocket, matches: F) -> Message {
+        let mut buf = [0; 65536];
+        loop {
+            let (len, _) = socket.recv_from(&mut buf).expect("no response");
+            if let Ok(msg) = Message::deserialize(&buf[..len]) {
+                if matches(&msg) {
+                    return msg;
+                }
+            }
+        }
+    }
+
+    /// Check that the server still stores and serves hashes
+    fn assert_alive(socket: &net::UdpSocket, server: net::SocketAddr) {
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let payload = Payload(b"still alive".to_vec());
+        let put = Message::Put(hash, payload.clone(), None);
+        socket.send_to(&put.serialize().unwrap(), server).unwrap();
+        socket.send_to(&Message::Get(hash).serialize().unwrap(), server).unwrap();
+        let response = expect(socket, |msg| matches!(*msg, Message::Put(_, _, _)));
+        assert_eq!(response, Message::Put(hash, payload, Some(30)));
+    }
+
+    #[test]
+    fn survive_garbage() {
+        let config = Config {
+            reply_errors: true,
+            ..Config::default()
+        };
+        with_server(config, |socket, server| {
+            // Send a bunch of random datagrams (xorshift), skipping the ones that are valid
+            let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+            let mut sent = 0;
+            while sent < 100 {
+                let len = (seed % 64) as usize;
+                let buf: Vec<u8> = (0..len)
+                    .map(|_| {
+                        seed ^= seed << 13;
+                        seed ^= seed >> 7;
+                        seed ^= seed << 17;
+                        seed as u8
+                    })
+                    .collect();
+                if Message::deserialize(&buf).is_ok() {
+                    continue;
+                }
+                socket.send_to(&buf, server).unwrap();
+                sent += 1;
+            }
+
+            // Those are answered with errors…
+            expect(&socket, |msg| {
+                matches!(*msg, Message::Error(ErrorCode::InvalidMessage, _))
+            });
+
+            // …and the server still works
+            assert_alive(&socket, server);
+        });
+    }
+
+    #[test]
+    fn slow_connections() {
+        let core = Core::new().unwrap();
+        let (connections, _messages) = Connections::new(&core.handle());
+        let node = net::SocketAddr::from(([127, 0, 0, 1], 1));
+
+        // A connection that doesn't take its frames is closed once too many are waiting
+        connections.open(node, future::empty::<TcpStream, io::Error>());
+        let sent = (0..MAX_PENDING_FRAMES * 2)
+            .take_while(|_| connections.send_frame(b"hi", &node).is_ok())
+            .count();
+        assert!(sent >= MAX_PENDING_FRAMES && sent < MAX_PENDING_FRAMES * 2);
+        assert!(!connections.is_open(&node));
+    }
+
+    #[test]
+    fn invalid_sources() {
+        let sources = InvalidSources::default();
+        let addr = |port| net::SocketAddr::from(([127, 0, 0, 1], port));
+        assert_eq!(sources.count(addr(1)), 1);
+        assert_eq!(sources.count(addr(1)), 2);
+
+        // Counting more sources than the limit starts over
+        for port in 2..=MAX_INVALID_SOURCES as u16 {
+            sources.count(addr(port));
+        }
+        assert_eq!(sources.0.borrow().len(), MAX_INVALID_SOURCES);
+        assert_eq!(sources.count(addr(1)), 3);
+        assert_eq!(sources.count(addr(0)), 1);
+        assert_eq!(sources.0.borrow().len(), 1);
+    }
+
+    #[test]
+    fn hello() {
+        with_server(Config::default(), |socket, server| {
+            let capabilities = Capabilities {
+                version: PROTOCOL_VERSION,
+                algorithms: vec![Algorithm::Sha256],
+                compression: Vec::new(),
+                max_payload: 1024,
+            };
+            let hello = Message::Hello(capabilities);
+
+            // Nodes that are not in the routing table are not answered
+            let stranger = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            stranger
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            stranger.send_to(&hello.serialize().unwrap(), server).unwrap();
+            assert!(stranger.recv_from(&mut [0; 65536]).is_err());
+
+            let keep_alive = Message::KeepAlive(Hash::from(7));
+            socket.send_to(&keep_alive.serialize().unwrap(), server).unwrap();
+            socket.send_to(&hello.serialize().unwrap(), server).unwrap();
+
+            // The server answers with its own capabilities, and uses the version both speak
+            let expected = Message::Hello(Capabilities::local());
+            let mut buf = [0; 65536];
+            loop {
+                let (len, _) = socket.recv_from(&mut buf).unwrap();
+                if buf[0] == 0x80 | PROTOCOL_VERSION {
+                    assert_eq!(Message::deserialize(&buf[..len]), Ok(expected));
+                    break;
+                }
+            }
+
+            // Hashes this node doesn't support are not sent to it: the first answer besides
+            // the routing messages is the one to the second PUT
+            let blake3 = Hash::of_content(Algorithm::Blake3, b"hi");
+            let put = Message::Put(blake3, Payload(b"hi".to_vec()), None);
+            socket.send_to(&put.serialize().unwrap(), server).unwrap();
+            let hash = Hash::from(42);
+            let put = Message::Put(hash, Payload(b"hi".to_vec()), None);
+            socket.send_to(&put.serialize().unwrap(), server).unwrap();
+            let response = loop {
+                let (len, _) = socket.recv_from(&mut buf).unwrap();
+                match Message::deserialize(&buf[..len]) {
+                    Ok(Message::Hello(_)) | Ok(Message::KeepAlive(_)) => continue,
+                    response => break response,
+                }
+            };
+            assert_eq!(buf[0], 0x80 | PROTOCOL_VERSION);
+            assert_eq!(response, Ok(Message::IHave(hash)));
+
+            assert_alive(&socket, server);
+        });
+    }
+
+    #[test]
+    fn request_ids() {
+        with_server(Config::default(), |socket, server| {
+            let mut buf = [0; 65536];
+            let hash = Hash::from(42);
+            let put = Message::Put(hash, Payload(b"hi".to_vec()), None);
+            let find = Message::FindNode(hash);
+            socket.send_to(&put.serialize_for(0, Some(1)).unwrap(), server).unwrap();
+            socket.send_to(&find.serialize_for(0, Some(2)).unwrap(), server).unwrap();
+
+            // Both the state and the routing table answer with the ID of the request
+            let mut responses = Vec::new();
+            for _ in 0..2 {
+                let (len, _) = socket.recv_from(&mut buf).unwrap();
+                responses.push(Message::deserialize_request(&buf[..len]).unwrap());
+            }
+            responses.sort_by_key(|&(request, _)| request);
+            assert_eq!(
+                responses,
+                vec![
+                    (Some(1), Message::Quorum(hash, 1, 1)),
+                    (Some(2), Message::Nodes(hash, Vec::new())),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn introduce() {
+        with_server(Config::default(), |socket, server| {
+            let mut buf = [0; 65536];
+            let contact = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            contact
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            let nodes = vec![(Hash::from(7), contact.local_addr().unwrap())];
+
+            // Nodes that weren't queried are not listened to
+            let message = Message::Nodes(Hash::from(1), nodes.clone());
+            socket.send_to(&message.serialize().unwrap(), server).unwrap();
+            assert!(contact.recv_from(&mut buf).is_err());
+
+            // Once queried, the contacts a node gives are told about this node before the query
+            let discover = Message::Discover(socket.local_addr().unwrap());
+            socket.send_to(&discover.serialize().unwrap(), server).unwrap();
+            let target = match expect(&socket, |msg| matches!(*msg, Message::FindNode(_))) {
+                Message::FindNode(target) => target,
+                _ => unreachable!(),
+            };
+            let message = Message::Nodes(target, nodes);
+            socket.send_to(&message.serialize().unwrap(), server).unwrap();
+            let (len, _) = contact.recv_from(&mut buf).unwrap();
+            let response = Message::deserialize(&buf[..len]);
+            assert!(matches!(response, Ok(Message::KeepAlive(_))));
+            let (len, _) = contact.recv_from(&mut buf).unwrap();
+            assert_eq!(Message::deserialize(&buf[..len]), Ok(Message::FindNode(target)));
+
+            // Answering twice doesn't start new queries
+            socket.send_to(&message.serialize().unwrap(), server).unwrap();
+            assert!(contact.recv_from(&mut buf).is_err());
+        });
+    }
+
+    #[test]
+    fn watch() {
+        // Datagrams can't watch, their source may be spoofed
+        with_server(Config::default(), |socket, server| {
+            let mut buf = [0; 65536];
+            let watch = Message::Watch(Hash::from(42));
+            socket.send_to(&watch.serialize_for(0, Some(1)).unwrap(), server).unwrap();
+            let (len, _) = socket.recv_from(&mut buf).unwrap();
+            assert!(matches!(
+                Message::deserialize_request(&buf[..len]),
+                Ok((Some(1), Message::Error(ErrorCode::InvalidRequest, _)))
+            ));
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let state = State::default();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = serve_tcp(&state, listener, &Config::default(), &handle);
+
+        run_client(&mut core, server, move || {
+            let mut stream = net::TcpStream::connect(server_addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let send = |stream: &mut net::TcpStream, msg: Message| {
+                let frame = msg.serialize_for(0, Some(1)).unwrap();
+                stream.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+                stream.write_all(&frame).unwrap();
+            };
+            let read = |stream: &mut net::TcpStream| {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut frame).unwrap();
+                Message::deserialize_request(&frame).unwrap()
+            };
+
+            let hash = Hash::from(42);
+            send(&mut stream, Message::Watch(hash));
+            assert_eq!(read(&mut stream), (Some(1), Message::Watch(hash)));
+
+            // Another client puts the hash, and the watcher is told, without the value
+            let mut other = net::TcpStream::connect(server_addr).unwrap();
+            send(&mut other, Message::Put(hash, Payload(b"hi".to_vec()), Some(60)));
+            assert_eq!(read(&mut stream), (Some(1), Message::IHave(hash)));
+        });
+    }
+
+    #[test]
+    fn tcp() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let state = State::default();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let config = Config {
+            reply_errors: true,
+            ..Config::default()
+        };
+        let server = serve_tcp(&state, listener, &config, &handle);
+
+        run_client(&mut core, server, move || {
+            let mut stream = net::TcpStream::connect(server_addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            // Messages follow their length, and invalid ones don't close the connection
+            let hash = Hash::from(42);
+            let payload = Payload(vec![42; 60_000]);
+            let put = Message::Put(hash, payload.clone(), None);
+            for frame in &[
+                vec![0x1f],
+                put.serialize_for(0, Some(1)).unwrap(),
+                Message::Get(hash).serialize_for(0, Some(2)).unwrap(),
+            ] {
+                stream.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+                stream.write_all(frame).unwrap();
+            }
+
+            // Answers come back on the same connection
+            let mut responses = Vec::new();
+            for _ in 0..3 {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut frame).unwrap();
+                responses.push(Message::deserialize_request(&frame).unwrap());
+            }
+            responses.sort_by_key(|&(request, _)| request);
+            assert!(matches!(
+                responses[0],
+                (None, Message::Error(ErrorCode::InvalidMessage, _))
+            ));
+            assert_eq!(responses[1], (Some(1), Message::Quorum(hash, 1, 1)));
+            assert_eq!(responses[2], (Some(2), Message::Put(hash, payload, Some(30))));
+
+            // Nodes first tell the port they listen to, and are answered all the same
+            let mut node = net::TcpStream::connect(server_addr).unwrap();
+            node.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut preamble = LISTENING.to_vec();
+            preamble.extend_from_slice(&4242u16.to_be_bytes());
+            assert_eq!(listening_port(&preamble), Some(4242));
+            let find = Message::FindNode(Hash::from(42)).serialize_for(0, Some(3)).unwrap();
+            for frame in &[preamble.clone(), find] {
+                node.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+                node.write_all(frame).unwrap();
+            }
+            let read_frame = |stream: &mut net::TcpStream| {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut frame).unwrap();
+                Message::deserialize_request(&frame)
+            };
+            assert!(matches!(read_frame(&mut node), Ok((Some(3), Message::Nodes(..)))));
+
+            // Another connection claiming the same port keeps its own address, and gets its
+            // answers rather than the ones of the node…
+            let mut impostor = net::TcpStream::connect(server_addr).unwrap();
+            impostor.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let find = Message::FindNode(Hash::from(42)).serialize_for(0, Some(4)).unwrap();
+            for frame in &[preamble, find] {
+                impostor.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+                impostor.write_all(frame).unwrap();
+            }
+            assert!(matches!(read_frame(&mut impostor), Ok((Some(4), Message::Nodes(..)))));
+
+            // …until the node is gone, when it takes the address over, and is written to
+            drop(node);
+            thread::sleep(Duration::from_millis(100));
+            let discover = Message::Discover(net::SocketAddr::from(([127, 0, 0, 1], 4242)));
+            let frame = discover.serialize().unwrap();
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(&frame).unwrap();
+            let keep_alive = read_frame(&mut impostor);
+            assert!(matches!(keep_alive, Ok((None, Message::KeepAlive(_)))));
+        });
+    }
+
+    #[test]
+    fn unix_socket() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let state = State::default();
+        let path = env::temp_dir().join(format!("simple_dht-{}.sock", process::id()));
+        let listener = UnixListener::bind(&path, &handle).unwrap();
+        let server = serve_unix(&state, listener, &Config::default(), &handle);
+
+        let client_path = path.clone();
+        run_client(&mut core, server, move || {
+            let mut stream = unix::net::UnixStream::connect(client_path).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+
+            // Local clients use the TCP framing, and are answered like the prompt
+            let hash = Hash::from(42);
+            let put = Message::Put(hash, Payload(b"local".to_vec()), None);
+            let frame = put.serialize_for(0, Some(3)).unwrap();
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).unwrap();
+            stream.write_all(&frame).unwrap();
+
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut frame).unwrap();
+            assert_eq!(
+                Message::deserialize_request(&frame),
+                Ok((Some(3), Message::Quorum(hash, 1, 1)))
+            );
+        });
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn survive_unreachable() {
+        with_server(Config::default(), |socket, server| {
+            // Sending to the broadcast address is refused by the OS
+            let peer = "255.255.255.255:4242".parse().unwrap();
+            let discover = Message::Discover(peer);
+            socket.send_to(&discover.serialize().unwrap(), server).unwrap();
+
+            // The server keeps working while it broadcasts to it
+            assert_alive(&socket, server);
+            assert_alive(&socket, server);
+        });
+    }
 }
@@ -1,89 +1,139 @@
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
 use std::sync::Arc;
 use futures::{future, stream, task, Async, Future, Poll, Stream};
 use futures::sync::mpsc;
 use tokio_timer::{Timer, TimerError};
 
-use messages::{Hash, Message, Payload};
+use messages::{ErrorCode, Hash, Message, NodeId, Payload, RequestId};
+use routing::RoutingTable;
+use storage::{HashStore, Stats, Storage, TTL};
 
-/// Time to live for peers and hashes, in seconds
-static TTL: u64 = 30;
+/// How long a hash request waits for an answer, in seconds
+static REQUEST_TIMEOUT: u64 = 5;
 
-/// Stores one hash content
-#[derive(Debug, Clone)]
-struct Content {
-    /// Hash content
-    data: Vec<u8>,
-    /// The last time this hash was seen
-    pushed: Instant,
-}
+/// How long a hash is watched, in seconds, unless the watcher renews its lease
+pub static WATCH_LEASE: u64 = 60;
 
-impl Content {
-    fn from_buffer(data: Vec<u8>) -> Self {
-        Content {
-            pushed: Instant::now(),
-            data: data,
-        }
-    }
+/// How many hashes are watched at most, by all the watchers together
+static MAX_WATCHES: usize = 1024;
 
-    /// Check if content is considered as stale
-    fn is_stale(&self) -> bool {
-        self.pushed.elapsed() > Duration::from_secs(TTL)
-    }
+/// How many hashes each client or peer can watch at most
+/// The prompt is trusted, and only bound by MAX_WATCHES
+static MAX_WATCHES_PER_SOURCE: usize = 16;
+
+/// How many notifications wait to be sent to a watcher
+/// Watchers too slow to keep up stop watching, rather than queuing notifications without end
+static MAX_PENDING_NOTIFICATIONS: usize = 64;
+
+/// How often the hashes pushed by clients are announced again, in seconds
+/// A third of the default time to live, so that replicas fetching them get most of it
+static REPUBLISH_INTERVAL: u64 = 10;
+
+/// How many hashes are announced again at most every second
+/// Each one is broadcast, and the listeners only queue a few messages
+static REPUBLISH_BATCH: usize = 8;
+
+/// How many seconds a stored hash will still be kept, as sent in PUT messages
+fn remaining_ttl(store: &dyn Storage, hash: &Hash) -> Option<u32> {
+    store.ttl(hash).map(|ttl| {
+        // Round up, a hash still kept for a few milliseconds shouldn't be sent as already stale
+        let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        secs.min(u64::from(u32::MAX)) as u32
+    })
 }
 
-/// Stores hashes
-#[derive(Debug, Default)]
-struct HashStore {
-    hashes: HashMap<Hash, Content>,
+/// Where a processed message comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// The interactive prompt
+    Local,
+    /// A client, which isn't part of the network
+    Client(SocketAddr),
+    /// A local client connected to the Unix socket, numbered as it connected
+    Socket(usize),
+    /// Another node
+    Peer(SocketAddr),
 }
 
-impl HashStore {
-    /// Put a hash inside the store
-    /// Existing value will be overwritten
-    pub fn put(&mut self, hash: &Hash, data: Vec<u8>) {
-        self.hashes.insert(*hash, Content::from_buffer(data));
-    }
+/// How many nodes store each hash, and how many of them must answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replication {
+    /// How many nodes store each hash
+    pub replicas: usize,
+    /// How many replicas must answer a GET
+    pub read_quorum: usize,
+    /// How many replicas must hold a PUT before it is acknowledged
+    pub write_quorum: usize,
+}
 
-    /// Try to get a hash content from the store
-    pub fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
-        self.hashes.get(hash).map(|content| content.data.clone())
+impl Default for Replication {
+    fn default() -> Self {
+        Replication {
+            replicas: 3,
+            read_quorum: 1,
+            write_quorum: 1,
+        }
     }
+}
 
-    pub fn contains(&self, hash: &Hash) -> bool {
-        self.hashes.contains_key(hash)
+impl Replication {
+    /// Check that the quorums can be reached
+    pub fn validate(&self) -> Result<(), ReplicationError> {
+        let valid = |quorum| quorum >= 1 && quorum <= self.replicas;
+        if self.replicas <= usize::from(u8::MAX) && valid(self.read_quorum)
+            && valid(self.write_quorum)
+        {
+            Ok(())
+        } else {
+            Err(ReplicationError)
+        }
     }
+}
 
-    /// List known hashes
-    pub fn list(&self) -> Vec<&Hash> {
-        self.hashes.keys().collect()
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationError;
 
-    /// Cleanup stale hashes
-    pub fn cleanup(&mut self) {
-        self.hashes.retain(|_, content| !content.is_stale());
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("quorums must be between 1 and the replication factor, which is at most 255")
     }
 }
 
+impl Error for ReplicationError {}
+
 /// Stores listeners to broadcast messages
 #[derive(Default, Debug)]
 struct Listeners(Vec<mpsc::Sender<Message>>);
 
 impl Listeners {
     /// Broadcast a message to all listeners
+    /// The listeners that went away, like closed subscriptions, are forgotten
     pub fn broadcast(&mut self, msg: &Message) -> Result<(), mpsc::TrySendError<Message>> {
-        for listener in self.0.as_mut_slice() {
-            listener.try_send(msg.clone())?;
-        }
-
-        Ok(())
+        let mut result = Ok(());
+        self.0.retain_mut(|listener| match listener.try_send(msg.clone()) {
+            Err(ref e) if e.is_disconnected() => false,
+            Err(e) => {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+                true
+            }
+            Ok(()) => true,
+        });
+        result
     }
 
     /// Subscribe to broadcast messages
     pub fn subscribe(&mut self) -> mpsc::Receiver<Message> {
-        let (sender, receiver) = mpsc::channel(1);
+        // Forwarded lookups and notifications can be broadcast in bursts
+        let (sender, receiver) = mpsc::channel(16);
         self.0.push(sender);
         receiver
     }
@@ -95,31 +145,63 @@ struct Requests(HashMap<Hash, Vec<HashRequest>>);
 
 impl Requests {
     /// Request a Hash
-    /// The returned HashRequest is a future that resolves with the hash payload when found
-    pub fn request(&mut self, hash: Hash) -> HashRequest {
-        // FIXME: timeout?
-        let request = HashRequest::default();
-        self.0
-            .entry(hash)
-            .or_insert_with(Vec::new)
-            .push(request.clone());
+    /// The returned HashRequest is a future that resolves with the hash payload when found, or
+    /// fails once the timeout is elapsed
+    pub fn request(&mut self, hash: Hash, timeout: Duration) -> HashRequest {
+        let request = HashRequest::new(Instant::now() + timeout);
+        self.0.entry(hash).or_default().push(request.clone());
         request
     }
 
+    /// Check if a Hash is already being looked up
+    pub fn is_pending(&self, hash: &Hash) -> bool {
+        self.0.contains_key(hash)
+    }
+
     /// Fulfill requests from the store
-    pub fn fulfill(&mut self, store: &HashStore) {
-        for hash in store.list() {
-            if let Some(requests) = self.0.remove(hash) {
-                // FIXME: are those unwrap safe?
-                let payload = Payload(store.get(hash).unwrap());
-                for mut request in requests {
+    pub fn fulfill(&mut self, store: &dyn Storage) {
+        self.0.retain(|hash, requests| match store.get(hash) {
+            Some(data) => {
+                let payload = Payload(data);
+                for request in requests {
                     request.fulfill(payload.clone());
                 }
+                false
             }
+            None => true,
+        });
+    }
+
+    /// Fail requests past their deadline, and forget the ones that were dropped or cancelled
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for requests in self.0.values_mut() {
+            requests.retain_mut(|request| {
+                if request.is_done() || request.is_dropped() {
+                    return false;
+                }
+
+                if request.deadline <= now {
+                    request.fail(RequestError::Timeout);
+                    return false;
+                }
+
+                true
+            });
         }
+        self.0.retain(|_, requests| !requests.is_empty());
     }
 }
 
+/// Why a request could not be fulfilled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// Nobody answered before the deadline
+    Timeout,
+    /// The request was cancelled by its owner
+    Cancelled,
+}
+
 /// A single request
 #[derive(Debug, Clone)]
 pub struct HashRequest {
@@ -127,44 +209,313 @@ pub struct HashRequest {
     // This is needed to trigger a poll when the request is fulfilled
     task: Arc<RefCell<Option<task::Task>>>,
 
-    // The payload, if it was fulfilled
-    inner: Arc<RefCell<Option<Payload>>>,
+    // The payload if it was fulfilled, or why it failed
+    inner: Arc<RefCell<Option<Result<Payload, RequestError>>>>,
+
+    // When the request times out
+    deadline: Instant,
 }
 
 impl HashRequest {
+    fn new(deadline: Instant) -> Self {
+        HashRequest {
+            task: Arc::from(RefCell::from(None)),
+            inner: Arc::from(RefCell::from(None)),
+            deadline,
+        }
+    }
+
     /// Fulfill the request with a payload
     pub fn fulfill(&mut self, payload: Payload) {
+        self.resolve(Ok(payload));
+    }
+
+    /// Cancel the request
+    /// It will be removed from the pending requests on the next server loop
+    pub fn cancel(&mut self) {
+        self.fail(RequestError::Cancelled);
+    }
+
+    fn fail(&mut self, error: RequestError) {
+        self.resolve(Err(error));
+    }
+
+    fn resolve(&mut self, result: Result<Payload, RequestError>) {
+        if self.is_done() {
+            return;
+        }
+
         if let Some(ref task) = *self.task.borrow() {
             // tell the executor to poll this future
             task.notify();
         }
 
-        // Save payload content
-        *self.inner.borrow_mut() = Some(payload);
+        // Save the outcome
+        *self.inner.borrow_mut() = Some(result);
+    }
+
+    /// Check if the request was either fulfilled or failed
+    fn is_done(&self) -> bool {
+        self.inner.borrow().is_some()
+    }
+
+    /// Check if nobody is waiting on this request anymore
+    fn is_dropped(&self) -> bool {
+        Arc::strong_count(&self.inner) == 1
     }
 }
 
-impl Default for HashRequest {
-    fn default() -> Self {
-        HashRequest {
+impl Future for HashRequest {
+    type Item = Payload;
+    type Error = RequestError;
+
+    fn poll(&mut self) -> Poll<Payload, RequestError> {
+        if self.task.borrow().is_none() {
+            // save the task, see HashRequest::resolve
+            *self.task.borrow_mut() = Some(task::current());
+        }
+
+        match *self.inner.borrow() {
+            Some(Ok(ref payload)) => Ok(Async::Ready(payload.clone())),
+            Some(Err(e)) => Err(e),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Hashes clients pushed to this node, and when they were last announced
+/// This node keeps them alive: it refreshes and announces them again regularly, so that their
+/// replicas refresh them too, and new owners fetch them
+#[derive(Default, Debug)]
+struct Publications(HashMap<Hash, Instant>);
+
+impl Publications {
+    /// Start republishing a hash
+    pub fn publish(&mut self, hash: Hash) {
+        self.0.insert(hash, Instant::now());
+    }
+
+    /// Stop republishing a hash
+    pub fn unpublish(&mut self, hash: &Hash) {
+        self.0.remove(hash);
+    }
+
+    /// Refresh the hashes that weren't announced for `interval`, returning the ones to announce
+    /// At most `limit` hashes are refreshed, and the ones that left the store are forgotten
+    pub fn republish(
+        &mut self,
+        store: &mut dyn Storage,
+        interval: Duration,
+        limit: usize,
+    ) -> Vec<Hash> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        self.0.retain(|hash, announced| {
+            if due.len() >= limit || now.duration_since(*announced) < interval {
+                return true;
+            }
+
+            match store.refresh(hash) {
+                Ok(true) => {
+                    *announced = now;
+                    due.push(*hash);
+                    true
+                }
+                Ok(false) => false,
+                Err(e) => {
+                    error!("Could not refresh {:?}: {}", hash, e);
+                    true
+                }
+            }
+        });
+        due
+    }
+}
+
+/// Who watches a hash, told apart by the request it watches with
+type Watcher = (Origin, Option<RequestId>);
+
+/// The watchers of each hash, until when they watch it, and where to notify them
+#[derive(Default, Debug)]
+struct Watches(HashMap<Hash, HashMap<Watcher, (Instant, mpsc::Sender<Message>)>>);
+
+impl Watches {
+    /// Watch a hash until a lease expires, returning where the notifications come
+    /// Watching it again renews the lease, and ends the notifications of the previous one.
+    /// Returns None if there are too many watches already
+    pub fn watch(
+        &mut self,
+        hash: Hash,
+        watcher: Watcher,
+        expires: Instant,
+    ) -> Option<mpsc::Receiver<Message>> {
+        let renewed = self.0.get(&hash).is_some_and(|watchers| watchers.contains_key(&watcher));
+        if !renewed {
+            let watches = self.0.values().flat_map(HashMap::keys);
+            let (total, from_source) = watches.fold((0, 0), |(total, from_source), &(origin, _)| {
+                (total + 1, from_source + usize::from(origin == watcher.0))
+            });
+            let trusted = watcher.0 == Origin::Local;
+            if total >= MAX_WATCHES || (!trusted && from_source >= MAX_WATCHES_PER_SOURCE) {
+                return None;
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel(MAX_PENDING_NOTIFICATIONS);
+        self.0
+            .entry(hash)
+            .or_default()
+            .insert(watcher, (expires, sender));
+        Some(receiver)
+    }
+
+    /// Stop watching a hash, ending the notifications
+    pub fn unwatch(&mut self, hash: &Hash, watcher: &Watcher) {
+        if let Some(watchers) = self.0.get_mut(hash) {
+            watchers.remove(watcher);
+            if watchers.is_empty() {
+                self.0.remove(hash);
+            }
+        }
+    }
+
+    /// Notify the watchers of a hash, forgetting the ones that went away
+    /// The watches of the watchers too slow to take their notifications end
+    pub fn notify(&mut self, hash: &Hash, msg: &Message) {
+        if let Some(watchers) = self.0.get_mut(hash) {
+            watchers.retain(|watcher, &mut (_, ref mut sender)| match sender.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(ref e) if e.is_full() => {
+                    warn!("Watcher {:?} too slow, ending its watch of {:?}", watcher.0, hash);
+                    false
+                }
+                Err(_) => false,
+            });
+            if watchers.is_empty() {
+                self.0.remove(hash);
+            }
+        }
+    }
+
+    /// Forget the watchers whose lease ran out
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for watchers in self.0.values_mut() {
+            watchers.retain(|_, &mut (expires, _)| expires > now);
+        }
+        self.0.retain(|_, watchers| !watchers.is_empty());
+    }
+}
+
+/// What the replicas of a quorum acknowledge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Acknowledgement {
+    /// The replica holds the hash, after a PUT or to answer a GET
+    Held,
+    /// The replica deleted the hash
+    Deleted,
+}
+
+/// Stores pending quorums
+#[derive(Default, Debug)]
+struct Quorums(HashMap<(Hash, Acknowledgement), Vec<QuorumRequest>>);
+
+impl Quorums {
+    /// Wait for a number of replicas to acknowledge something about a hash
+    pub fn wait(
+        &mut self,
+        hash: Hash,
+        ack: Acknowledgement,
+        required: usize,
+        timeout: Duration,
+    ) -> QuorumRequest {
+        let request = QuorumRequest::new(required, Instant::now() + timeout);
+        self.0.entry((hash, ack)).or_default().push(request.clone());
+        request
+    }
+
+    /// Count a replica acknowledging something about a hash, `None` being the local node
+    /// Only the quorums waiting for that acknowledgement count it
+    pub fn acknowledge(&mut self, hash: &Hash, ack: Acknowledgement, replica: Option<SocketAddr>) {
+        if let Some(requests) = self.0.get_mut(&(*hash, ack)) {
+            for request in requests {
+                request.acknowledge(replica);
+            }
+        }
+    }
+
+    /// Wake up quorums past their deadline, and forget the ones that resolved or were dropped
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for requests in self.0.values_mut() {
+            requests.retain(|request| {
+                if request.deadline <= now {
+                    request.notify();
+                    return false;
+                }
+
+                !request.is_reached() && Arc::strong_count(&request.replicas) > 1
+            });
+        }
+        self.0.retain(|_, requests| !requests.is_empty());
+    }
+}
+
+/// Waits for replicas to hold or answer with a hash
+/// Resolves with how many did, once enough did or the deadline passed
+#[derive(Debug, Clone)]
+pub struct QuorumRequest {
+    // The current task in which the future is executed, see HashRequest
+    task: Arc<RefCell<Option<task::Task>>>,
+
+    // The replicas that answered, `None` being the local node
+    replicas: Arc<RefCell<HashSet<Option<SocketAddr>>>>,
+
+    // How many replicas must answer
+    required: usize,
+
+    // When to stop waiting
+    deadline: Instant,
+}
+
+impl QuorumRequest {
+    fn new(required: usize, deadline: Instant) -> Self {
+        QuorumRequest {
             task: Arc::from(RefCell::from(None)),
-            inner: Arc::from(RefCell::from(None)),
+            replicas: Arc::default(),
+            required,
+            deadline,
+        }
+    }
+
+    fn acknowledge(&mut self, replica: Option<SocketAddr>) {
+        if self.replicas.borrow_mut().insert(replica) && self.is_reached() {
+            self.notify();
+        }
+    }
+
+    fn is_reached(&self) -> bool {
+        self.replicas.borrow().len() >= self.required
+    }
+
+    fn notify(&self) {
+        if let Some(ref task) = *self.task.borrow() {
+            task.notify();
         }
     }
 }
 
-impl Future for HashRequest {
-    type Item = Payload;
+impl Future for QuorumRequest {
+    type Item = usize;
     type Error = ();
 
-    fn poll(&mut self) -> Poll<Payload, ()> {
+    fn poll(&mut self) -> Poll<usize, ()> {
         if self.task.borrow().is_none() {
-            // save the task, see HashRequest::fulfill
             *self.task.borrow_mut() = Some(task::current());
         }
 
-        if let Some(ref payload) = *self.inner.borrow() {
-            Ok(Async::Ready(payload.clone()))
+        if self.is_reached() || self.deadline <= Instant::now() {
+            Ok(Async::Ready(self.replicas.borrow().len()))
         } else {
             Ok(Async::NotReady)
         }
@@ -172,53 +523,319 @@ impl Future for HashRequest {
 }
 
 /// The server state
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct State {
+    /// This node ID
+    id: NodeId,
     /// Listeners subscribed to broadcasts
     listeners: Arc<RefCell<Listeners>>,
     /// Where the hashes are stored
-    hashes: Arc<RefCell<HashStore>>,
+    hashes: Arc<RefCell<Box<dyn Storage>>>,
     /// Pending hash requests
     requests: Arc<RefCell<Requests>>,
+    /// Pending quorum reads and writes
+    quorums: Arc<RefCell<Quorums>>,
+    /// Hashes kept alive by this node
+    publications: Arc<RefCell<Publications>>,
+    /// Who is notified of the changes of each hash
+    watches: Arc<RefCell<Watches>>,
+    /// Subscribers to the changes of every hash
+    changes: Arc<RefCell<Listeners>>,
+    /// The routing tables of the listeners
+    tables: Arc<RefCell<Vec<Rc<RefCell<RoutingTable>>>>>,
+    /// How many nodes store each hash
+    replication: Replication,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::new(Replication::default()).expect("Invalid default replication")
+    }
 }
 
 impl State {
+    /// Create an empty state, storing hashes in memory
+    pub fn new(replication: Replication) -> Result<Self, ReplicationError> {
+        State::with_storage(replication, Box::new(HashStore::default()))
+    }
+
+    /// Create a state storing hashes in the given backend
+    /// Fails if the replication settings are invalid
+    pub fn with_storage(
+        replication: Replication,
+        storage: Box<dyn Storage>,
+    ) -> Result<Self, ReplicationError> {
+        replication.validate()?;
+        Ok(State {
+            id: NodeId::random(),
+            listeners: Arc::default(),
+            hashes: Arc::from(RefCell::from(storage)),
+            requests: Arc::default(),
+            quorums: Arc::default(),
+            publications: Arc::default(),
+            watches: Arc::default(),
+            changes: Arc::default(),
+            tables: Arc::default(),
+            replication,
+        })
+    }
+
+    /// This node ID
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// How many nodes store each hash
+    pub fn replication(&self) -> Replication {
+        self.replication
+    }
+
     /// Process a Message, returning a Stream of Messages to respond
-    pub fn process(&self, msg: Message) -> Box<Stream<Item = Message, Error = ()>> {
+    /// The responses carry the ID of the request, if it had one
+    pub fn process(
+        &self,
+        msg: Message,
+        request: Option<RequestId>,
+        origin: Origin,
+    ) -> Box<dyn Stream<Item = (Option<RequestId>, Message), Error = ()>> {
+        let responses = match msg {
+            Message::Watch(hash) => self.watch(hash, (origin, request)),
+            Message::Unwatch(hash) => {
+                info!("Message: UNWATCH {:?}", hash);
+                self.watches.borrow_mut().unwatch(&hash, &(origin, request));
+                Box::new(stream::once(Ok(Message::Unwatch(hash))))
+            }
+            msg => self.respond(msg, origin),
+        };
+        Box::new(responses.map(move |msg| (request, msg)))
+    }
+
+    /// Watch a hash for a lease, answered once, and then with an IHAVE or a DELETE every time
+    /// the hash is put or deleted. The notifications stop when the lease runs out, or when the
+    /// watcher renews it
+    fn watch(&self, hash: Hash, watcher: Watcher) -> Box<dyn Stream<Item = Message, Error = ()>> {
+        info!("Message: WATCH {:?}", hash);
+        let expires = Instant::now() + Duration::from_secs(WATCH_LEASE);
+        match self.watches.borrow_mut().watch(hash, watcher, expires) {
+            Some(notifications) => {
+                Box::new(stream::once(Ok(Message::Watch(hash))).chain(notifications))
+            }
+            None => {
+                warn!("Too many watches to watch {:?} for {:?}", hash, watcher.0);
+                let reason = String::from("too many watches");
+                Box::new(stream::once(Ok(Message::Error(ErrorCode::InvalidRequest, reason))))
+            }
+        }
+    }
+
+    /// Build the responses to a Message
+    fn respond(&self, msg: Message, origin: Origin) -> Box<dyn Stream<Item = Message, Error = ()>> {
+        let is_peer = matches!(origin, Origin::Peer(_));
         let opt = match msg {
+            Message::Get(hash) if self.is_deleted(&hash) => {
+                info!("Message: GET {:?} (deleted)", hash);
+                Some(Message::NotFound(hash))
+            }
             Message::Get(hash) => {
                 info!("Message: GET {:?}", hash);
+                // Peers only need one answer, clients wait for the read quorum
+                let required = if is_peer { 1 } else { self.replication.read_quorum };
+                // Only ask the peers if nobody is already looking this hash up, and if more
+                // replicas have to answer
+                let forward = (!self.contains(&hash) || required > 1) && !self.is_pending(&hash);
                 // Request the hash from the store
-                let req = self.request(hash.clone());
+                let req = self.request(hash);
                 // try to immediately fullfill the request
-                self.requests.borrow_mut().fulfill(&self.hashes.borrow());
-                // and stream it to the client
-                return Box::new(
-                    req.map(move |payload| Message::Put(hash, payload))
-                        .into_stream()
-                        .map_err(|e| error!("{:?}", e)),
-                );
+                self.requests.borrow_mut().fulfill(&**self.hashes.borrow());
+                if forward {
+                    // Ask every known peer: the first PUT to come back fulfills all the pending
+                    // requests for this hash
+                    debug!("Forwarding GET {:?} to peers", hash);
+                    if let Err(e) = self.broadcast(&Message::Get(hash)) {
+                        error!("Could not forward GET {:?}: {}", hash, e);
+                    }
+                }
+                // and stream it to the client, or tell it nobody had the hash
+                let hashes = Arc::clone(&self.hashes);
+                let response = req.then(move |result| match result {
+                    Ok(payload) => {
+                        let ttl = remaining_ttl(&**hashes.borrow(), &hash);
+                        Ok(Some(Message::Put(hash, payload, ttl)))
+                    }
+                    Err(RequestError::Timeout) => Ok(Some(Message::NotFound(hash))),
+                    Err(RequestError::Cancelled) => Ok(None),
+                });
+                if is_peer {
+                    return Box::new(response.into_stream().filter_map(|msg| msg));
+                }
+
+                // Clients are also told how many replicas answered
+                let quorum = self.quorum(hash, required);
+                let response = response.join(quorum).map(move |(msg, replicas)| {
+                    let quorum = Some(Message::Quorum(hash, replicas as u8, required as u8));
+                    let msgs = match msg {
+                        Some(Message::Put(_, _, _)) => vec![msg, quorum],
+                        // Nobody answered in time, which doesn't mean nobody holds the hash
+                        Some(Message::NotFound(_)) => vec![quorum],
+                        _ => vec![msg],
+                    };
+                    stream::iter_ok(msgs.into_iter().flatten())
+                });
+                return Box::new(response.flatten_stream());
             }
-            Message::Put(hash, Payload(p)) => {
+            Message::Put(hash, Payload(p), ttl) => {
                 info!("Message: PUT {:?} [{} bytes]", hash, p.len());
-                // Put the hash in the store
-                self.put(&hash, p);
-                self.requests.borrow_mut().fulfill(&self.hashes.borrow());
+                // Content-addressed hashes only hold their own content
+                if !hash.matches(&p) {
+                    warn!("Payload of {:?} does not match its hash", hash);
+                    let reason = String::from("payload does not match its hash");
+                    return Box::new(stream::once(Ok(Message::Error(
+                        ErrorCode::InvalidRequest,
+                        reason,
+                    ))));
+                }
+                // A stale replica answering an older request would bring a deleted hash back,
+                // but the hash can be put again
+                let revived = self.is_deleted(&hash);
+                if revived && is_peer && self.is_pending(&hash) {
+                    debug!("Not storing deleted {:?}", hash);
+                    // Tell the replica the hash is gone
+                    return Box::new(stream::once(Ok(Message::Delete(hash))));
+                }
+                // Replicas still holding a tombstone would answer an IHAVE with a DELETE, they
+                // are sent the hash itself
+                let announce = if revived && !is_peer {
+                    Message::Put(hash, Payload(p.clone()), ttl)
+                } else {
+                    Message::IHave(hash)
+                };
+                // Put the hash in the store, for as long as the sender asked
+                let ttl_secs = ttl.map(u64::from).unwrap_or(TTL);
+                if let Err(e) = self.put(&hash, p, Duration::from_secs(ttl_secs)) {
+                    error!("Could not store {:?}: {}", hash, e);
+                    let reason = String::from("could not store hash");
+                    return Box::new(stream::once(Ok(Message::Error(ErrorCode::Internal, reason))));
+                }
+                self.requests.borrow_mut().fulfill(&**self.hashes.borrow());
+                if let Origin::Peer(addr) = origin {
+                    // The peer holds this hash too
+                    let ack = Acknowledgement::Held;
+                    self.quorums.borrow_mut().acknowledge(&hash, ack, Some(addr));
+                } else if ttl.is_none() {
+                    // Keep the hashes clients pushed alive, unless they asked for them to expire
+                    self.publications.borrow_mut().publish(hash);
+                }
+                if revived && is_peer {
+                    // The other replicas are sent the hash by the node it was put on
+                    return Box::new(stream::once(Ok(Message::IHave(hash))));
+                }
                 // and broadcast a notification to everyone
-                self.broadcast(&Message::IHave(hash)).unwrap();
-                None
+                match self.broadcast(&announce) {
+                    // The sender may not be a known peer, tell it directly
+                    Ok(()) if is_peer => Some(Message::IHave(hash)),
+                    // Clients wait until enough replicas hold the hash
+                    Ok(()) => {
+                        let required = self.replication.write_quorum;
+                        let response = self.quorum(hash, required).map(move |replicas| {
+                            Message::Quorum(hash, replicas as u8, required as u8)
+                        });
+                        return Box::new(response.into_stream());
+                    }
+                    Err(e) => {
+                        error!("Could not announce {:?}: {}", hash, e);
+                        let reason = String::from("could not notify peers");
+                        Some(Message::Error(ErrorCode::Internal, reason))
+                    }
+                }
+            }
+            Message::Discover(addr) if addr.ip().is_unspecified() || addr.port() == 0 => {
+                info!("Message: DISCOVER {}", addr);
+                Some(Message::Error(
+                    ErrorCode::InvalidRequest,
+                    format!("cannot discover unspecified address {}", addr),
+                ))
             }
             Message::Discover(addr) => {
                 info!("Message: DISCOVER {}", addr);
                 // The listeners *should* intercept this DISCOVER message and add the new peer to
                 // their known peer list
-                self.broadcast(&msg).unwrap();
+                self.broadcast(&msg).err().map(|e| {
+                    error!("Could not broadcast DISCOVER {}: {}", addr, e);
+                    Message::Error(ErrorCode::Internal, String::from("could not add peer"))
+                })
+            }
+            Message::NotFound(hash) => {
+                // A peer could not find a hash we forwarded, others may still answer
+                debug!("Message: NOTFOUND {:?}", hash);
+                None
+            }
+            Message::Error(code, reason) => {
+                // Never answer errors, this could loop between two nodes
+                warn!("Message: ERROR {:?} {}", code, reason);
                 None
             }
-            Message::IHave(hash) if !self.contains(&hash) => {
+            Message::IHave(hash) => {
                 info!("Message: IHAVE {:?}", hash);
-                // Someone has a hash that I don't have: get it from him!
-                Some(Message::Get(hash))
+                if let Origin::Peer(addr) = origin {
+                    // This may be a replica acknowledging a PUT
+                    let ack = Acknowledgement::Held;
+                    self.quorums.borrow_mut().acknowledge(&hash, ack, Some(addr));
+                }
+                if self.is_deleted(&hash) {
+                    // Someone still keeps a deleted hash alive: tell it to delete it too
+                    Some(Message::Delete(hash))
+                } else if self.contains(&hash) {
+                    // Someone keeps this hash alive: keep my copy too
+                    if let Err(e) = self.hashes.borrow_mut().refresh(&hash) {
+                        error!("Could not refresh {:?}: {}", hash, e);
+                    }
+                    None
+                } else {
+                    // Someone has a hash that I don't have: get it from him!
+                    Some(Message::Get(hash))
+                }
+            }
+            Message::Delete(hash) => {
+                info!("Message: DELETE {:?}", hash);
+                if let Origin::Peer(addr) = origin {
+                    // This may be a replica acknowledging a DELETE
+                    let ack = Acknowledgement::Deleted;
+                    self.quorums.borrow_mut().acknowledge(&hash, ack, Some(addr));
+                    if self.is_deleted(&hash) {
+                        // Already deleted: don't answer, this could loop between two nodes
+                        return Box::new(stream::empty());
+                    }
+                }
+                if let Err(e) = self.delete(&hash) {
+                    error!("Could not delete {:?}: {}", hash, e);
+                    let reason = String::from("could not delete hash");
+                    return Box::new(stream::once(Ok(Message::Error(ErrorCode::Internal, reason))));
+                }
+                if is_peer {
+                    // Tell the peer this replica deleted it too
+                    Some(Message::Delete(hash))
+                } else {
+                    match self.broadcast(&Message::Delete(hash)) {
+                        // Clients wait until enough replicas deleted the hash
+                        Ok(()) => {
+                            let required = self.replication.write_quorum;
+                            let mut quorums = self.quorums.borrow_mut();
+                            let timeout = Duration::from_secs(REQUEST_TIMEOUT);
+                            let ack = Acknowledgement::Deleted;
+                            let response = quorums.wait(hash, ack, required, timeout);
+                            quorums.acknowledge(&hash, ack, None);
+                            let response = response.map(move |replicas| {
+                                Message::Quorum(hash, replicas as u8, required as u8)
+                            });
+                            return Box::new(response.into_stream());
+                        }
+                        Err(e) => {
+                            error!("Could not broadcast DELETE {:?}: {}", hash, e);
+                            let reason = String::from("could not notify peers");
+                            Some(Message::Error(ErrorCode::Internal, reason))
+                        }
+                    }
+                }
             }
             m => {
                 warn!("Ignored message {:?}", m);
@@ -243,33 +860,140 @@ impl State {
         self.listeners.borrow_mut().subscribe()
     }
 
+    /// Subscribe to the changes of the hashes stored by this node, whoever made them
+    /// An IHAVE tells a hash was put, a DELETE that it was deleted
+    pub fn subscribe_changes(&self) -> mpsc::Receiver<Message> {
+        self.changes.borrow_mut().subscribe()
+    }
+
+    /// Tell the watchers of a hash and the subscribers to changes that it was put or deleted
+    fn changed(&self, hash: &Hash, msg: &Message) {
+        self.watches.borrow_mut().notify(hash, msg);
+        if let Err(e) = self.changes.borrow_mut().broadcast(msg) {
+            warn!("Could not tell a subscriber about {:?}: {}", hash, e);
+        }
+    }
+
     /// Request a Hash
-    /// The returned HashRequest is a future that resolves with the hash payload when found
+    /// The returned HashRequest is a future that resolves with the hash payload when found, or
+    /// fails after REQUEST_TIMEOUT seconds
     pub fn request(&self, hash: Hash) -> HashRequest {
-        self.requests.borrow_mut().request(hash)
+        self.requests
+            .borrow_mut()
+            .request(hash, Duration::from_secs(REQUEST_TIMEOUT))
+    }
+
+    /// Wait for replicas to hold or answer with a hash
+    /// The local node counts as one if it holds the hash. The returned QuorumRequest resolves with
+    /// how many replicas did once `required` did, or after REQUEST_TIMEOUT seconds
+    pub fn quorum(&self, hash: Hash, required: usize) -> QuorumRequest {
+        let mut quorums = self.quorums.borrow_mut();
+        let ack = Acknowledgement::Held;
+        let request = quorums.wait(hash, ack, required, Duration::from_secs(REQUEST_TIMEOUT));
+        if self.contains(&hash) {
+            quorums.acknowledge(&hash, ack, None);
+        }
+        request
     }
 
     /// Put a hash inside the store
     /// Existing value will be overwritten
-    pub fn put(&self, hash: &Hash, data: Vec<u8>) {
-        self.hashes.borrow_mut().put(hash, data);
+    /// The watchers of the hash and the subscribers to changes are told if it changed, and get it
+    /// if they want its value: a copy of the same value, like a replica answering a lookup, only
+    /// changes it if it is kept longer
+    pub fn put(&self, hash: &Hash, data: Vec<u8>, ttl: Duration) -> io::Result<()> {
+        let mut hashes = self.hashes.borrow_mut();
+        // Peers send the time left rounded up to the second. Comparing doesn't count as a use
+        let changed = !hashes.same_content(hash, &data)
+            || hashes
+                .ttl(hash)
+                .is_none_or(|left| ttl > left + Duration::from_secs(1));
+        hashes.put(hash, data, ttl)?;
+        drop(hashes);
+        if changed {
+            self.changed(hash, &Message::IHave(*hash));
+        }
+        Ok(())
+    }
+
+    /// How many seconds a hash will still be kept
+    pub fn ttl(&self, hash: &Hash) -> Option<u32> {
+        remaining_ttl(&**self.hashes.borrow(), hash)
     }
 
     /// Try to get a hash content from the store
     pub fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
-        self.hashes.borrow_mut().get(hash)
+        self.hashes.borrow().get(hash)
+    }
+
+    /// Check if a hash is in the store
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.borrow().contains(hash)
+    }
+
+    /// Delete a hash, and stop keeping it alive
+    /// Its tombstone lasts at least as long as the copies other replicas may still hold
+    pub fn delete(&self, hash: &Hash) -> io::Result<()> {
+        let mut hashes = self.hashes.borrow_mut();
+        let existed = hashes.contains(hash);
+        let ttl = hashes
+            .ttl(hash)
+            .unwrap_or_default()
+            .max(Duration::from_secs(TTL));
+        hashes.delete(hash, ttl)?;
+        drop(hashes);
+        self.publications.borrow_mut().unpublish(hash);
+        // Deleting a hash that isn't there changes nothing
+        if existed {
+            self.changed(hash, &Message::Delete(*hash));
+        }
+        Ok(())
+    }
+
+    /// Check if a hash was deleted recently
+    pub fn is_deleted(&self, hash: &Hash) -> bool {
+        self.hashes.borrow().is_deleted(hash)
     }
 
-    fn contains(&self, hash: &Hash) -> bool {
-        self.hashes.borrow_mut().contains(hash)
+    /// Check if a Hash is being looked up
+    pub fn is_pending(&self, hash: &Hash) -> bool {
+        self.requests.borrow().is_pending(hash)
+    }
+
+    /// Share the routing table of a listener, so that its nodes are listed as peers
+    pub fn add_routing_table(&self, table: Rc<RefCell<RoutingTable>>) {
+        self.tables.borrow_mut().push(table);
+    }
+
+    /// The nodes known by the listeners, with their ID
+    /// A node known by several listeners is listed once for each
+    pub fn peers(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.tables
+            .borrow()
+            .iter()
+            .flat_map(|table| {
+                let table = table.borrow();
+                table.closest(&self.id, table.len())
+            })
+            .collect()
+    }
+
+    /// Statistics about the hash store
+    pub fn stats(&self) -> Stats {
+        self.hashes.borrow().stats()
     }
 
     /// Run the server loop
-    pub fn run(&self) -> Box<Future<Item = (), Error = ()>> {
+    pub fn run(&self) -> Box<dyn Future<Item = (), Error = ()>> {
         debug!("Starting server loop");
         let listeners = Arc::clone(&self.listeners);
         let hashes = Arc::clone(&self.hashes);
         let requests = Arc::clone(&self.requests);
+        let quorums = Arc::clone(&self.quorums);
+        let publications = Arc::clone(&self.publications);
+        let watches = Arc::clone(&self.watches);
+        let keep_alive = Message::KeepAlive(self.id);
+        let mut evictions = self.stats().evictions;
 
         let timer = Timer::default();
         let interval = timer.interval(Duration::from_secs(1));
@@ -277,10 +1001,34 @@ impl State {
             // This is run every second
             debug!("Tick.");
             hashes.borrow_mut().cleanup(); // Cleanup stale hashes
-            requests.borrow_mut().fulfill(&hashes.borrow()); // Fulfill pending requests
+            let stats = hashes.borrow().stats();
+            if stats.evictions > evictions {
+                info!(
+                    "Evicted {} hashes to stay within the store limits ({} in total, {} bytes)",
+                    stats.evictions - evictions,
+                    stats.evictions,
+                    stats.evicted_bytes
+                );
+                evictions = stats.evictions;
+            }
+            requests.borrow_mut().fulfill(&**hashes.borrow()); // Fulfill pending requests
+            requests.borrow_mut().expire(); // Expire the ones that waited too long
+            quorums.borrow_mut().expire(); // Stop waiting for replicas that didn't answer
+            watches.borrow_mut().expire(); // Forget the watchers that didn't renew their lease
+            let republished = publications.borrow_mut().republish(
+                &mut **hashes.borrow_mut(),
+                Duration::from_secs(REPUBLISH_INTERVAL),
+                REPUBLISH_BATCH,
+            );
+            for hash in republished {
+                // Announce the hashes kept alive here again
+                if let Err(e) = listeners.borrow_mut().broadcast(&Message::IHave(hash)) {
+                    error!("Could not republish {:?}: {}", hash, e);
+                }
+            }
             listeners // and broadcast KeepAlive to everyone
                 .borrow_mut()
-                .broadcast(&Message::KeepAlive)
+                .broadcast(&keep_alive)
                 .map_err(|e| error!("Could not broadcast KeepAlive: {}", e))
         });
         Box::new(timer_stream.for_each(|_| future::ok(())))
@@ -289,51 +1037,545 @@ impl State {
 
 #[cfg(test)]
 mod tests {
-    use super::State;
+    use super::{
+        Acknowledgement, Origin, Publications, Quorums, Replication, RequestError, Requests, State, Watches,
+        MAX_PENDING_NOTIFICATIONS, MAX_WATCHES, MAX_WATCHES_PER_SOURCE,
+    };
+    use std::io;
     use std::str::FromStr;
-    use futures::{Async, Stream};
-    use messages::{Hash, Message};
+    use std::time::{Duration, Instant};
+    use futures::{Async, Poll, Stream};
+    use futures::executor::{spawn, Notify, Spawn};
+    use messages::{Algorithm, ErrorCode, Hash, Message, Payload};
+    use storage::{HashStore, Storage};
+
+    /// Lets streams be polled outside of an event loop
+    struct Noop;
+
+    impl Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+
+    static NOOP: Noop = Noop;
+
+    fn poll<S: Stream>(stream: &mut Spawn<S>) -> Poll<Option<S::Item>, S::Error> {
+        stream.poll_stream_notify(&&NOOP, 0)
+    }
 
     #[test]
     fn store_hashes() {
         let state = State::default();
         let hash = Hash::from_str("0123456789abcdef").unwrap();
         let content = vec![24, 8, 42, 12];
-        assert_eq!(state.get(&hash.clone()), None);
+        assert_eq!(state.get(&hash), None);
 
-        state.put(&hash.clone(), content.clone());
+        state.put(&hash, content.clone(), Duration::from_secs(60)).unwrap();
         assert_eq!(state.get(&hash), Some(content));
     }
 
+    /// A backend that can't store anything
+    #[derive(Debug)]
+    struct FullStorage;
+
+    impl Storage for FullStorage {
+        fn put(&mut self, _hash: &Hash, _data: Vec<u8>, _ttl: Duration) -> io::Result<()> {
+            Err(io::Error::other("no space left"))
+        }
+
+        fn get(&self, _hash: &Hash) -> Option<Vec<u8>> {
+            None
+        }
+
+        fn ttl(&self, _hash: &Hash) -> Option<Duration> {
+            None
+        }
+
+        fn refresh(&mut self, _hash: &Hash) -> io::Result<bool> {
+            Ok(false)
+        }
+
+        fn delete(&mut self, _hash: &Hash, _ttl: Duration) -> io::Result<()> {
+            Err(io::Error::other("read-only"))
+        }
+
+        fn is_deleted(&self, _hash: &Hash) -> bool {
+            false
+        }
+
+        fn list(&self) -> Vec<Hash> {
+            Vec::new()
+        }
+
+        fn cleanup(&mut self) {}
+
+        fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Hash, Vec<u8>)> + 'a> {
+            Box::new(None.into_iter())
+        }
+    }
+
+    #[test]
+    fn storage_errors() {
+        let state = State::with_storage(Replication::default(), Box::new(FullStorage)).unwrap();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let mut listener = spawn(state.subscribe());
+
+        // A hash that can't be stored is neither announced nor acknowledged
+        let put = Message::Put(hash, Payload(vec![24, 8, 42, 12]), None);
+        let mut stream = spawn(state.process(put, None, Origin::Local));
+        match poll(&mut stream) {
+            Ok(Async::Ready(Some((None, Message::Error(ErrorCode::Internal, _))))) => (),
+            other => panic!("Unexpected response {:?}", other),
+        }
+        assert_eq!(poll(&mut listener), Ok(Async::NotReady));
+        assert!(!state.contains(&hash));
+    }
+
+    #[test]
+    fn content_addressed() {
+        let state = State::default();
+        let content = b"hello".to_vec();
+        let hash = Hash::of_content(Algorithm::Sha256, &content);
+        let mut listener = spawn(state.subscribe());
+
+        // A payload that doesn't match its content-addressed hash is refused, even from peers
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let put = Message::Put(hash, Payload(b"world".to_vec()), None);
+        let mut stream = spawn(state.process(put, None, peer));
+        match poll(&mut stream) {
+            Ok(Async::Ready(Some((None, Message::Error(ErrorCode::InvalidRequest, _))))) => (),
+            other => panic!("Unexpected response {:?}", other),
+        }
+        assert_eq!(poll(&mut listener), Ok(Async::NotReady));
+        assert!(!state.contains(&hash));
+
+        // The right one is stored
+        let put = Message::Put(hash, Payload(content.clone()), None);
+        spawn(state.process(put, None, peer)).wait_stream();
+        assert_eq!(state.get(&hash), Some(content));
+    }
+
+    #[test]
+    fn watch_hashes() {
+        let state = State::default();
+        let hash = Hash::from(42);
+        let client = Origin::Client("127.0.0.1:4242".parse().unwrap());
+
+        let mut watch = spawn(state.process(Message::Watch(hash), Some(5), client));
+        assert_eq!(poll(&mut watch), Ok(Async::Ready(Some((Some(5), Message::Watch(hash))))));
+        assert_eq!(poll(&mut watch), Ok(Async::NotReady));
+
+        // Watchers are told the hash changed, without its value, and when it is deleted
+        let put = Message::Put(hash, Payload(vec![1, 2, 3]), Some(60));
+        spawn(state.process(put, None, Origin::Local)).wait_stream();
+        assert_eq!(poll(&mut watch), Ok(Async::Ready(Some((Some(5), Message::IHave(hash))))));
+        state.delete(&hash).unwrap();
+        assert_eq!(poll(&mut watch), Ok(Async::Ready(Some((Some(5), Message::Delete(hash))))));
+
+        // Renewing the lease moves the notifications to the new response
+        let mut renewed = spawn(state.process(Message::Watch(hash), Some(5), client));
+        assert_eq!(poll(&mut watch), Ok(Async::Ready(None)));
+        assert_eq!(poll(&mut renewed), Ok(Async::Ready(Some((Some(5), Message::Watch(hash))))));
+
+        // Other requests are other watchers
+        let mut unwatch = spawn(state.process(Message::Unwatch(hash), Some(6), client));
+        let expected = Message::Unwatch(hash);
+        assert_eq!(poll(&mut unwatch), Ok(Async::Ready(Some((Some(6), expected)))));
+        assert_eq!(poll(&mut renewed), Ok(Async::NotReady));
+        spawn(state.process(Message::Unwatch(hash), Some(5), client)).wait_stream();
+        assert_eq!(poll(&mut renewed), Ok(Async::Ready(None)));
+    }
+
+    #[test]
+    fn expire_watches() {
+        let mut watches = Watches::default();
+        let hash = Hash::from(42);
+        let now = Instant::now();
+        let mut expired = spawn(watches.watch(hash, (Origin::Local, Some(1)), now).unwrap());
+        let later = now + Duration::from_secs(60);
+        let mut watching = spawn(watches.watch(hash, (Origin::Local, Some(2)), later).unwrap());
+
+        watches.expire();
+        assert!(watches.0.contains_key(&hash));
+        watches.notify(&hash, &Message::Delete(hash));
+        assert_eq!(poll(&mut expired), Ok(Async::Ready(None)));
+        assert_eq!(poll(&mut watching), Ok(Async::Ready(Some(Message::Delete(hash)))));
+
+        // Watchers that went away are forgotten
+        drop(watching);
+        watches.notify(&hash, &Message::Delete(hash));
+        assert!(!watches.0.contains_key(&hash));
+
+        // The watches of watchers too slow to take their notifications end, once they got the
+        // ones already waiting
+        let mut slow = spawn(watches.watch(hash, (Origin::Local, Some(3)), later).unwrap());
+        for _ in 0..MAX_PENDING_NOTIFICATIONS * 2 {
+            watches.notify(&hash, &Message::IHave(hash));
+        }
+        assert!(!watches.0.contains_key(&hash));
+        let mut notified = 0;
+        while let Ok(Async::Ready(Some(_))) = poll(&mut slow) {
+            notified += 1;
+        }
+        assert!(notified >= MAX_PENDING_NOTIFICATIONS && notified < MAX_PENDING_NOTIFICATIONS * 2);
+        assert_eq!(poll(&mut slow), Ok(Async::Ready(None)));
+    }
+
+    #[test]
+    fn limit_watches() {
+        let mut watches = Watches::default();
+        let expires = Instant::now() + Duration::from_secs(60);
+        let client = Origin::Client("127.0.0.1:4242".parse().unwrap());
+        let other = Origin::Client("127.0.0.1:4343".parse().unwrap());
+
+        // Each client is bound, renewing a lease doesn't count as another watch
+        for i in 0..MAX_WATCHES_PER_SOURCE {
+            assert!(watches.watch(Hash::from(i as u64), (client, None), expires).is_some());
+        }
+        assert!(watches.watch(Hash::from(0), (client, None), expires).is_some());
+        assert!(watches.watch(Hash::from(0), (client, Some(1)), expires).is_none());
+        assert!(watches.watch(Hash::from(0), (other, None), expires).is_some());
+
+        // So is each local client, unlike the prompt which only shares the global bound
+        for i in 0..MAX_WATCHES_PER_SOURCE {
+            let hash = Hash::from(i as u64);
+            assert!(watches.watch(hash, (Origin::Socket(1), None), expires).is_some());
+        }
+        assert!(watches.watch(Hash::from(0), (Origin::Socket(1), Some(1)), expires).is_none());
+        assert!(watches.watch(Hash::from(0), (Origin::Socket(2), Some(1)), expires).is_some());
+        for i in 0..MAX_WATCHES - 2 * MAX_WATCHES_PER_SOURCE - 2 {
+            assert!(watches.watch(Hash::from(i as u64), (Origin::Local, None), expires).is_some());
+        }
+        let mut watch = |watcher| watches.watch(Hash::from(u64::MAX), watcher, expires);
+        assert!(watch((Origin::Local, Some(1))).is_none());
+        assert!(watch((Origin::Client("127.0.0.1:4444".parse().unwrap()), None)).is_none());
+    }
+
+    #[test]
+    fn changes() {
+        let state = State::default();
+        let hash = Hash::from(42);
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let mut changes = spawn(state.subscribe_changes());
+
+        // Puts and deletions are seen whoever makes them, announces are not
+        let put = Message::Put(hash, Payload(vec![1, 2, 3]), None);
+        spawn(state.process(put, None, peer)).wait_stream();
+        assert_eq!(poll(&mut changes), Ok(Async::Ready(Some(Message::IHave(hash)))));
+        spawn(state.process(Message::IHave(hash), None, peer)).wait_stream();
+        assert_eq!(poll(&mut changes), Ok(Async::NotReady));
+
+        // Copies of the same value don't change it, unless they are kept longer
+        let copy = Message::Put(hash, Payload(vec![1, 2, 3]), Some(30));
+        spawn(state.process(copy, None, peer)).wait_stream();
+        assert_eq!(poll(&mut changes), Ok(Async::NotReady));
+        let longer = Message::Put(hash, Payload(vec![1, 2, 3]), Some(3600));
+        spawn(state.process(longer, None, peer)).wait_stream();
+        assert_eq!(poll(&mut changes), Ok(Async::Ready(Some(Message::IHave(hash)))));
+        spawn(state.process(Message::Delete(hash), None, peer)).wait_stream();
+        assert_eq!(poll(&mut changes), Ok(Async::Ready(Some(Message::Delete(hash)))));
+        spawn(state.process(Message::Delete(hash), None, peer)).wait_stream();
+        assert_eq!(poll(&mut changes), Ok(Async::NotReady));
+    }
+
+    #[test]
+    fn forget_listeners() {
+        let state = State::default();
+        let hash = Hash::from(42);
+        let mut listener = spawn(state.subscribe());
+        drop(state.subscribe());
+
+        // A listener that went away doesn't keep the others from being notified
+        let put = Message::Put(hash, Payload(vec![1, 2, 3]), None);
+        let mut stream = spawn(state.process(put, None, Origin::Local));
+        assert_eq!(poll(&mut listener), Ok(Async::Ready(Some(Message::IHave(hash)))));
+        let expected = Message::Quorum(hash, 1, 1);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+    }
+
     #[test]
     fn process_messages() {
         let state = State::default();
         let hash = Hash::from_str("0123456789abcdef").unwrap();
-
-        /* FIXME: The HashRequest needs an Executor for testing
         let content = vec![24, 8, 42, 12];
-        let mut listener = state.subscribe();
-
-        // `Put` should yield a `IHave` message
-        let mut stream = state.process(Message::Put(hash.clone(), Payload(content.clone())));
-        let expected = Message::IHave(hash.clone());
-        assert_eq!(listener.poll(), Ok(Async::Ready(Some(expected))));
-        assert_eq!(stream.poll(), Ok(Async::Ready(None)));
-
-        // `Get` should yield a `Put` message
-        let mut stream = state.process(Message::Get(hash.clone()));
-        let expected = Message::Put(hash.clone(), Payload(content.clone()));
-        assert_eq!(stream.poll(), Ok(Async::Ready(Some(expected))));
-        assert_eq!(stream.poll(), Ok(Async::Ready(None)));
-        */
-
-        // `IHave` shouldn't do anything
-        let mut stream = state.process(Message::IHave(hash.clone()));
-        let expected = Message::Get(hash.clone());
-        assert_eq!(stream.poll(), Ok(Async::Ready(Some(expected))));
+        let mut listener = spawn(state.subscribe());
+
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+
+        // `Put` should yield a `IHave` message broadcast, and a `Quorum` sent back
+        let put = Message::Put(hash, Payload(content.clone()), Some(3600));
+        let mut stream = spawn(state.process(put.clone(), None, Origin::Local));
+        let expected = Message::IHave(hash);
+        assert_eq!(poll(&mut listener), Ok(Async::Ready(Some(expected))));
+        let expected = Message::Quorum(hash, 1, 1);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(None)));
+
+        // Peers get a `IHave` back instead
+        let mut stream = spawn(state.process(put, None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, Message::IHave(hash))))));
+
+        // `Get` should yield a `Put` message with the time left, followed by a `Quorum` for clients
+        let mut stream = spawn(state.process(Message::Get(hash), None, Origin::Local));
+        let expected = Message::Put(hash, Payload(content.clone()), Some(3600));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        let expected = Message::Quorum(hash, 1, 1);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(None)));
+
+        let mut stream = spawn(state.process(Message::Get(hash), None, peer));
+        let expected = Message::Put(hash, Payload(content.clone()), Some(3600));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(None)));
+
+        // Responses carry the ID of their request
+        let mut stream = spawn(state.process(Message::Get(hash), Some(7), Origin::Local));
+        let expected = Message::Put(hash, Payload(content), Some(3600));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((Some(7), expected)))));
+        let expected = Message::Quorum(hash, 1, 1);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((Some(7), expected)))));
+
+        // `IHave` for an unknown hash should yield a `Get` message
+        let other = Hash::from_str("fedcba9876543210").unwrap();
+        let mut stream = spawn(state.process(Message::IHave(other), None, peer));
+        let expected = Message::Get(other);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
 
         // `KeepAlive` shouldn't do anything
-        let mut stream = state.process(Message::KeepAlive);
-        assert_eq!(stream.poll(), Ok(Async::Ready(None)));
+        let mut stream = spawn(state.process(Message::KeepAlive(Hash::from(42)), None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(None)));
+
+        // Invalid requests are answered with an `Error`
+        let addr = "0.0.0.0:1234".parse().unwrap();
+        let mut stream = spawn(state.process(Message::Discover(addr), None, Origin::Local));
+        match poll(&mut stream) {
+            Ok(Async::Ready(Some((None, Message::Error(ErrorCode::InvalidRequest, _))))) => (),
+            other => panic!("Unexpected response {:?}", other),
+        }
+
+        // but errors never are
+        let msg = Message::Error(ErrorCode::Internal, String::from("oops"));
+        let mut stream = spawn(state.process(msg, None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(None)));
+    }
+
+    #[test]
+    fn delete_hashes() {
+        let state = State::default();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let payload = Payload(vec![24, 8, 42, 12]);
+        let mut listener = spawn(state.subscribe());
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let put = Message::Put(hash, payload.clone(), None);
+        spawn(state.process(put.clone(), None, Origin::Local)).wait_stream();
+        assert_eq!(poll(&mut listener), Ok(Async::Ready(Some(Message::IHave(hash)))));
+
+        // `Delete` is broadcast, and acknowledged once enough replicas deleted the hash
+        let mut stream = spawn(state.process(Message::Delete(hash), None, Origin::Local));
+        assert_eq!(poll(&mut listener), Ok(Async::Ready(Some(Message::Delete(hash)))));
+        let expected = Message::Quorum(hash, 1, 1);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        assert!(!state.contains(&hash));
+        assert!(state.publications.borrow().0.is_empty());
+
+        // Deleted hashes are not found, and stale replicas are told to delete them too
+        let mut stream = spawn(state.process(Message::Get(hash), None, Origin::Local));
+        let expected = Message::NotFound(hash);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        let _request = state.request(hash);
+        let mut stream = spawn(state.process(put.clone(), None, peer));
+        let expected = Message::Delete(hash);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected.clone())))));
+        assert!(!state.contains(&hash));
+        let mut stream = spawn(state.process(Message::IHave(hash), None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+
+        // Peers acknowledge a `Delete` only once
+        let mut stream = spawn(state.process(Message::Delete(hash), None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(None)));
+        let other = Hash::from_str("fedcba9876543210").unwrap();
+        let mut stream = spawn(state.process(Message::Delete(other), None, peer));
+        let expected = Message::Delete(other);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+
+        // Clients can put it again, and the replicas are sent the hash itself
+        let mut stream = spawn(state.process(put.clone(), None, Origin::Local));
+        assert_eq!(poll(&mut listener), Ok(Async::Ready(Some(put.clone()))));
+        let expected = Message::Quorum(hash, 1, 1);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        assert!(state.contains(&hash) && !state.is_deleted(&hash));
+
+        // So can peers sending it unasked, the node it was put on tells the others
+        state.delete(&hash).unwrap();
+        let mut stream = spawn(state.process(put, None, peer));
+        let expected = Message::IHave(hash);
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, expected)))));
+        assert!(state.contains(&hash));
+        assert_eq!(poll(&mut listener), Ok(Async::NotReady));
+    }
+
+    #[test]
+    fn forward_get() {
+        let state = State::default();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let content = vec![24, 8, 42, 12];
+        let mut listener = spawn(state.subscribe());
+
+        // A missing hash is looked up on the peers…
+        let mut first = spawn(state.process(Message::Get(hash), None, Origin::Local));
+        assert_eq!(poll(&mut first), Ok(Async::NotReady));
+        assert_eq!(poll(&mut listener), Ok(Async::Ready(Some(Message::Get(hash)))));
+
+        // …only once, even if it is requested again in the meantime
+        let mut second = spawn(state.process(Message::Get(hash), None, Origin::Local));
+        assert_eq!(poll(&mut second), Ok(Async::NotReady));
+        assert_eq!(poll(&mut listener), Ok(Async::NotReady));
+
+        // The first `Put` coming back is relayed to every requester
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let put = Message::Put(hash, Payload(content.clone()), None);
+        let mut stream = spawn(state.process(put, None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, Message::IHave(hash))))));
+        let expected = Message::Put(hash, Payload(content), Some(30));
+        assert_eq!(poll(&mut first), Ok(Async::Ready(Some((None, expected.clone())))));
+        assert_eq!(poll(&mut second), Ok(Async::Ready(Some((None, expected)))));
+    }
+
+    #[test]
+    fn quorums() {
+        // Quorums are between 1 and the replication factor, which fits in a message
+        let valid = |replicas, read_quorum, write_quorum| {
+            let replication = Replication {
+                replicas,
+                read_quorum,
+                write_quorum,
+            };
+            State::new(replication).is_ok()
+        };
+        assert!(valid(255, 1, 255));
+        assert!(!valid(256, 1, 1));
+        assert!(!valid(3, 0, 1));
+        assert!(!valid(3, 2, 4));
+
+        let state = State::new(Replication {
+            replicas: 3,
+            read_quorum: 2,
+            write_quorum: 3,
+        }).unwrap();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let payload = Payload(vec![24, 8, 42, 12]);
+        let a = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let b = Origin::Peer("127.0.0.1:4243".parse().unwrap());
+        let _listener = state.subscribe();
+
+        // A `Put` is acknowledged once every replica holds the hash, each counting once
+        let put = Message::Put(hash, payload.clone(), None);
+        let mut write = spawn(state.process(put, None, Origin::Local));
+        assert_eq!(poll(&mut write), Ok(Async::NotReady));
+        spawn(state.process(Message::IHave(hash), None, a)).wait_stream();
+        spawn(state.process(Message::IHave(hash), None, a)).wait_stream();
+        assert_eq!(poll(&mut write), Ok(Async::NotReady));
+        spawn(state.process(Message::IHave(hash), None, b)).wait_stream();
+        let expected = Message::Quorum(hash, 3, 3);
+        assert_eq!(poll(&mut write), Ok(Async::Ready(Some((None, expected)))));
+
+        // A `Get` is answered once another replica sent the hash back
+        let mut read = spawn(state.process(Message::Get(hash), None, Origin::Local));
+        assert_eq!(poll(&mut read), Ok(Async::NotReady));
+        spawn(state.process(Message::Put(hash, payload.clone(), None), None, b)).wait_stream();
+        let expected = Message::Put(hash, payload, Some(30));
+        assert_eq!(poll(&mut read), Ok(Async::Ready(Some((None, expected)))));
+        let expected = Message::Quorum(hash, 2, 2);
+        assert_eq!(poll(&mut read), Ok(Async::Ready(Some((None, expected)))));
+
+        // Quorums give up after their deadline
+        let mut quorums = Quorums::default();
+        let ack = Acknowledgement::Held;
+        let mut quorum = spawn(quorums.wait(hash, ack, 2, Duration::from_secs(0)));
+        quorums.expire();
+        assert_eq!(quorum.poll_future_notify(&&NOOP, 0), Ok(Async::Ready(0)));
+    }
+
+    #[test]
+    fn delete_quorums() {
+        let state = State::new(Replication {
+            replicas: 2,
+            read_quorum: 1,
+            write_quorum: 2,
+        }).unwrap();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let _listener = state.subscribe();
+        state.put(&hash, vec![24, 8, 42, 12], Duration::from_secs(60)).unwrap();
+
+        // A stale replica announcing the hash doesn't acknowledge its deletion
+        let mut delete = spawn(state.process(Message::Delete(hash), None, Origin::Local));
+        assert_eq!(poll(&mut delete), Ok(Async::NotReady));
+        let mut stream = spawn(state.process(Message::IHave(hash), None, peer));
+        assert_eq!(poll(&mut stream), Ok(Async::Ready(Some((None, Message::Delete(hash))))));
+        assert_eq!(poll(&mut delete), Ok(Async::NotReady));
+
+        // and deletions don't acknowledge puts
+        let put = Message::Put(hash, Payload(vec![1]), None);
+        let mut write = spawn(state.process(put, None, Origin::Local));
+        spawn(state.process(Message::Delete(hash), None, peer)).wait_stream();
+        assert_eq!(poll(&mut write), Ok(Async::NotReady));
+        let expected = Message::Quorum(hash, 2, 2);
+        assert_eq!(poll(&mut delete), Ok(Async::Ready(Some((None, expected)))));
+    }
+
+    #[test]
+    fn expire_requests() {
+        let mut requests = Requests::default();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+
+        // Requests past their deadline fail…
+        let mut req = spawn(requests.request(hash, Duration::from_secs(0)));
+        assert_eq!(req.poll_future_notify(&&NOOP, 0), Ok(Async::NotReady));
+        requests.expire();
+        assert_eq!(req.poll_future_notify(&&NOOP, 0), Err(RequestError::Timeout));
+        assert!(!requests.is_pending(&hash));
+
+        // …and the dropped or cancelled ones are forgotten
+        let mut req = requests.request(hash, Duration::from_secs(60));
+        drop(requests.request(hash, Duration::from_secs(60)));
+        requests.expire();
+        assert!(requests.is_pending(&hash));
+        req.cancel();
+        let mut req = spawn(req);
+        assert_eq!(req.poll_future_notify(&&NOOP, 0), Err(RequestError::Cancelled));
+        requests.expire();
+        assert!(!requests.is_pending(&hash));
+    }
+
+    #[test]
+    fn republish() {
+        let state = State::default();
+        let hash = Hash::from_str("0123456789abcdef").unwrap();
+        let other = Hash::from_str("fedcba9876543210").unwrap();
+        let payload = Payload(vec![24, 8, 42, 12]);
+        let _listener = state.subscribe();
+
+        // Only the hashes pushed by clients without a time to live are kept alive
+        let put = Message::Put(hash, payload.clone(), None);
+        spawn(state.process(put, None, Origin::Local)).wait_stream();
+        let put = Message::Put(other, payload.clone(), Some(60));
+        spawn(state.process(put, None, Origin::Local)).wait_stream();
+        let peer = Origin::Peer("127.0.0.1:4242".parse().unwrap());
+        let put = Message::Put(Hash::from(42), payload, None);
+        spawn(state.process(put, None, peer)).wait_stream();
+
+        let mut publications = state.publications.borrow_mut();
+        let mut store = state.hashes.borrow_mut();
+        let due = publications.republish(&mut **store, Duration::from_secs(60), 8);
+        assert!(due.is_empty());
+        let due = publications.republish(&mut **store, Duration::from_secs(0), 8);
+        assert_eq!(due, vec![hash]);
+
+        // The hashes that left the store are forgotten
+        let mut publications = Publications::default();
+        publications.publish(hash);
+        let mut empty = HashStore::default();
+        assert!(publications.republish(&mut empty, Duration::from_secs(0), 8).is_empty());
+        assert!(publications.0.is_empty());
     }
 }
@@ -0,0 +1,539 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use messages::Hash;
+
+/// Time to live for hashes pushed without one, in seconds
+pub static TTL: u64 = 30;
+
+/// How many tombstones a store keeps at most
+/// Past that, the ones expiring first are dropped to make room for new ones
+pub static MAX_TOMBSTONES: usize = 65_536;
+
+/// Where a node keeps the hashes it knows
+/// Backends only store hashes: lookups, replication and expiry of requests are handled by the
+/// state on top of them
+pub trait Storage: fmt::Debug {
+    /// Put a hash inside the store, to be kept for `ttl`
+    /// Existing value will be overwritten
+    fn put(&mut self, hash: &Hash, data: Vec<u8>, ttl: Duration) -> io::Result<()>;
+
+    /// Try to get a hash content from the store
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>>;
+
+    /// How long a hash will still be kept
+    fn ttl(&self, hash: &Hash) -> Option<Duration>;
+
+    /// Keep a hash for another time to live, as if it was just pushed
+    /// Returns false if the hash isn't in the store
+    fn refresh(&mut self, hash: &Hash) -> io::Result<bool>;
+
+    /// Remove a hash, leaving a tombstone in its place for `ttl`
+    /// The tombstone is removed by the next put of the hash. At most MAX_TOMBSTONES are kept
+    fn delete(&mut self, hash: &Hash, ttl: Duration) -> io::Result<()>;
+
+    /// Check if a hash was deleted, and its tombstone is still there
+    fn is_deleted(&self, hash: &Hash) -> bool;
+
+    /// Check if a hash is in the store
+    fn contains(&self, hash: &Hash) -> bool {
+        self.get(hash).is_some()
+    }
+
+    /// Check if a hash holds some content, without counting it as a use of the hash
+    fn same_content(&self, hash: &Hash, data: &[u8]) -> bool {
+        self.get(hash).as_deref() == Some(data)
+    }
+
+    /// List known hashes
+    fn list(&self) -> Vec<Hash>;
+
+    /// Cleanup stale hashes
+    fn cleanup(&mut self);
+
+    /// Iterate over the known hashes and their content
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Hash, Vec<u8>)> + 'a>;
+
+    /// Statistics about the store
+    fn stats(&self) -> Stats {
+        Stats {
+            entries: self.list().len(),
+            ..Stats::default()
+        }
+    }
+}
+
+/// Statistics about a store
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// How many hashes are stored
+    pub entries: usize,
+    /// Total size of the stored contents, in bytes
+    pub bytes: usize,
+    /// How many hashes were evicted to make room for new ones
+    pub evictions: u64,
+    /// Total size of the evicted contents, in bytes
+    pub evicted_bytes: u64,
+}
+
+/// Which hashes are evicted first when a bounded store is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// Evict the least recently used hash
+    #[default]
+    Lru,
+    /// Evict the least frequently used hash
+    Lfu,
+}
+
+impl FromStr for Eviction {
+    type Err = EvictionParseError;
+
+    fn from_str(s: &str) -> Result<Eviction, EvictionParseError> {
+        match s {
+            "lru" => Ok(Eviction::Lru),
+            "lfu" => Ok(Eviction::Lfu),
+            _ => Err(EvictionParseError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionParseError;
+
+impl fmt::Display for EvictionParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("expected one of lru or lfu")
+    }
+}
+
+impl Error for EvictionParseError {}
+
+/// Bounds on the memory used by a `HashStore`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum total size of the contents, in bytes
+    pub max_bytes: Option<usize>,
+    /// Maximum number of hashes
+    pub max_entries: Option<usize>,
+    /// Which hashes make room for new ones
+    pub eviction: Eviction,
+}
+
+impl Limits {
+    /// Check if a store with these entries and bytes is over the limits
+    fn exceeded(&self, entries: usize, bytes: usize) -> bool {
+        self.max_entries.is_some_and(|max| entries > max)
+            || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// Stores one hash content
+#[derive(Debug, Clone)]
+struct Content {
+    /// Hash content
+    data: Vec<u8>,
+    /// The last time this hash was seen
+    pushed: Instant,
+    /// How long this hash is kept after it was seen
+    ttl: Duration,
+    /// When this hash was last read, on the store clock
+    used: Cell<u64>,
+    /// How many times this hash was read
+    hits: Cell<u64>,
+}
+
+impl Content {
+    fn from_buffer(data: Vec<u8>, ttl: Duration) -> Self {
+        Content {
+            pushed: Instant::now(),
+            data,
+            ttl,
+            used: Cell::new(0),
+            hits: Cell::new(0),
+        }
+    }
+
+    /// How long this content will still be kept
+    fn remaining(&self) -> Duration {
+        self.ttl
+            .checked_sub(self.pushed.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Check if content is considered as stale
+    fn is_stale(&self) -> bool {
+        self.pushed.elapsed() > self.ttl
+    }
+
+    /// Where this content comes in the eviction order
+    fn rank(&self, eviction: Eviction) -> Rank {
+        match eviction {
+            Eviction::Lru => (self.used.get(), 0),
+            Eviction::Lfu => (self.hits.get(), self.used.get()),
+        }
+    }
+}
+
+/// Marks a deleted hash
+#[derive(Debug, Clone, Copy)]
+struct Tombstone {
+    /// When the hash was deleted
+    deleted: Instant,
+    /// How long the tombstone is kept
+    ttl: Duration,
+}
+
+impl Tombstone {
+    fn is_stale(&self) -> bool {
+        self.deleted.elapsed() > self.ttl
+    }
+
+    /// When the tombstone expires
+    fn expires(&self) -> Instant {
+        self.deleted + self.ttl
+    }
+}
+
+/// Where a hash comes in the eviction order, the lowest being evicted first
+type Rank = (u64, u64);
+
+/// Stores hashes in memory
+/// The store can be bounded, in which case hashes are evicted to make room for new ones
+#[derive(Debug, Default)]
+pub struct HashStore {
+    hashes: HashMap<Hash, Content>,
+    /// The hashes in eviction order, updated as they are used
+    order: RefCell<BTreeSet<(Rank, Hash)>>,
+    tombstones: HashMap<Hash, Tombstone>,
+    /// The tombstones in expiry order
+    expiries: BTreeSet<(Instant, Hash)>,
+    limits: Limits,
+    /// Total size of the contents
+    bytes: usize,
+    /// Logical clock, ticking on every access, used to order hashes by recency
+    clock: Cell<u64>,
+    evictions: u64,
+    evicted_bytes: u64,
+}
+
+impl HashStore {
+    /// Create a store bounded by some limits
+    pub fn with_limits(limits: Limits) -> Self {
+        HashStore {
+            limits,
+            ..HashStore::default()
+        }
+    }
+
+    /// Advance the clock
+    fn tick(&self) -> u64 {
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        now
+    }
+
+    /// Pick the next hash to evict, sparing one
+    fn victim(&self, spared: &Hash) -> Option<Hash> {
+        self.order
+            .borrow()
+            .iter()
+            .map(|&(_, hash)| hash)
+            .find(|hash| hash != spared)
+    }
+
+    /// Remove a hash content, and its place in the eviction order
+    fn remove(&mut self, hash: &Hash) -> Option<Content> {
+        let content = self.hashes.remove(hash)?;
+        self.order.get_mut().remove(&(content.rank(self.limits.eviction), *hash));
+        self.bytes -= content.data.len();
+        Some(content)
+    }
+
+    /// Remove the tombstone of a hash
+    fn remove_tombstone(&mut self, hash: &Hash) {
+        if let Some(tombstone) = self.tombstones.remove(hash) {
+            self.expiries.remove(&(tombstone.expires(), *hash));
+        }
+    }
+
+    /// Evict hashes until the store fits in its limits again
+    fn evict(&mut self, spared: &Hash) {
+        while self.limits.exceeded(self.hashes.len(), self.bytes) {
+            let hash = match self.victim(spared) {
+                Some(hash) => hash,
+                None => break,
+            };
+            let content = self.remove(&hash).unwrap();
+            debug!("Evicting {:?} ({} bytes)", hash, content.data.len());
+            self.evictions += 1;
+            self.evicted_bytes += content.data.len() as u64;
+        }
+    }
+}
+
+impl Storage for HashStore {
+    fn put(&mut self, hash: &Hash, data: Vec<u8>, ttl: Duration) -> io::Result<()> {
+        if self.limits.max_bytes.is_some_and(|max| data.len() > max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "content larger than the store",
+            ));
+        }
+
+        self.remove_tombstone(hash);
+        if let Some(content) = self.hashes.get_mut(hash) {
+            // A copy of the same content only moves its expiry, and keeps its place in the
+            // eviction order
+            if content.data == data {
+                content.pushed = Instant::now();
+                content.ttl = ttl;
+                return Ok(());
+            }
+        }
+        self.remove(hash);
+        let content = Content::from_buffer(data, ttl);
+        content.used.set(self.tick());
+        self.bytes += content.data.len();
+        self.order.get_mut().insert((content.rank(self.limits.eviction), *hash));
+        self.hashes.insert(*hash, content);
+        self.evict(hash);
+        Ok(())
+    }
+
+    fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+        self.hashes.get(hash).map(|content| {
+            let mut order = self.order.borrow_mut();
+            order.remove(&(content.rank(self.limits.eviction), *hash));
+            content.used.set(self.tick());
+            content.hits.set(content.hits.get() + 1);
+            order.insert((content.rank(self.limits.eviction), *hash));
+            content.data.clone()
+        })
+    }
+
+    fn ttl(&self, hash: &Hash) -> Option<Duration> {
+        self.hashes.get(hash).map(Content::remaining)
+    }
+
+    fn refresh(&mut self, hash: &Hash) -> io::Result<bool> {
+        Ok(self.hashes
+            .get_mut(hash)
+            .map(|content| content.pushed = Instant::now())
+            .is_some())
+    }
+
+    fn delete(&mut self, hash: &Hash, ttl: Duration) -> io::Result<()> {
+        self.remove(hash);
+        self.remove_tombstone(hash);
+        if self.tombstones.len() >= MAX_TOMBSTONES {
+            if let Some(&(_, oldest)) = self.expiries.iter().next() {
+                self.remove_tombstone(&oldest);
+            }
+        }
+        let tombstone = Tombstone {
+            deleted: Instant::now(),
+            ttl,
+        };
+        self.expiries.insert((tombstone.expires(), *hash));
+        self.tombstones.insert(*hash, tombstone);
+        Ok(())
+    }
+
+    fn is_deleted(&self, hash: &Hash) -> bool {
+        self.tombstones
+            .get(hash)
+            .is_some_and(|tombstone| !tombstone.is_stale())
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.contains_key(hash)
+    }
+
+    fn same_content(&self, hash: &Hash, data: &[u8]) -> bool {
+        self.hashes
+            .get(hash)
+            .is_some_and(|content| content.data == data)
+    }
+
+    fn list(&self) -> Vec<Hash> {
+        self.hashes.keys().cloned().collect()
+    }
+
+    fn cleanup(&mut self) {
+        let stale: Vec<Hash> = self.hashes
+            .iter()
+            .filter(|&(_, content)| content.is_stale())
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in &stale {
+            self.remove(hash);
+        }
+        // The tombstones expiring first come first
+        let now = Instant::now();
+        while let Some(&(expires, hash)) = self.expiries.iter().next() {
+            if expires >= now {
+                break;
+            }
+            self.remove_tombstone(&hash);
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Hash, Vec<u8>)> + 'a> {
+        Box::new(
+            self.hashes
+                .iter()
+                .map(|(hash, content)| (*hash, content.data.clone())),
+        )
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            entries: self.hashes.len(),
+            bytes: self.bytes,
+            evictions: self.evictions,
+            evicted_bytes: self.evicted_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Eviction, HashStore, Limits, Stats, Storage, MAX_TOMBSTONES};
+    use std::time::Duration;
+    use messages::Hash;
+
+    #[test]
+    fn memory_store() {
+        let mut store = HashStore::default();
+        let hash = Hash::from(42);
+        assert!(!store.contains(&hash));
+        assert_eq!(store.get(&hash), None);
+
+        let ttl = Duration::from_secs(60);
+        store.put(&hash, vec![1, 2, 3], ttl).unwrap();
+        store.put(&hash, vec![4, 5], ttl).unwrap();
+        assert!(store.contains(&hash));
+        assert_eq!(store.get(&hash), Some(vec![4, 5]));
+        assert_eq!(store.list(), vec![hash]);
+        assert_eq!(store.iter().collect::<Vec<_>>(), vec![(hash, vec![4, 5])]);
+
+        assert!(store.ttl(&hash).unwrap() <= ttl);
+
+        // Fresh hashes survive a cleanup, unlike the ones that outlived their time to live
+        let short = Hash::from(43);
+        store.put(&short, vec![6], Duration::from_secs(0)).unwrap();
+        assert_eq!(store.ttl(&short), Some(Duration::from_secs(0)));
+        store.cleanup();
+        assert!(store.contains(&hash));
+        assert!(!store.contains(&short));
+
+        // Only stored hashes can be refreshed
+        assert!(store.refresh(&hash).unwrap());
+        assert!(!store.refresh(&short).unwrap());
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut store = HashStore::default();
+        let hash = Hash::from(42);
+        let ttl = Duration::from_secs(60);
+        store.put(&hash, vec![1, 2, 3], ttl).unwrap();
+
+        // Deleted hashes are gone, but remembered until their tombstone expires
+        store.delete(&hash, ttl).unwrap();
+        assert!(!store.contains(&hash));
+        assert!(store.is_deleted(&hash));
+        assert_eq!(store.stats().bytes, 0);
+        let short = Hash::from(43);
+        store.delete(&short, Duration::from_secs(0)).unwrap();
+        store.cleanup();
+        assert!(store.is_deleted(&hash));
+        assert!(!store.is_deleted(&short));
+
+        // Putting a hash again brings it back
+        store.put(&hash, vec![4], ttl).unwrap();
+        assert!(!store.is_deleted(&hash));
+        assert_eq!(store.get(&hash), Some(vec![4]));
+
+        // The tombstones expiring first make room for new ones
+        store.delete(&hash, Duration::from_secs(1)).unwrap();
+        for i in 0..MAX_TOMBSTONES as u64 {
+            store.delete(&Hash::from(i + 100), ttl).unwrap();
+        }
+        assert_eq!(store.tombstones.len(), MAX_TOMBSTONES);
+        assert_eq!(store.expiries.len(), MAX_TOMBSTONES);
+        assert!(!store.is_deleted(&hash));
+        assert!(store.is_deleted(&Hash::from(100)));
+    }
+
+    #[test]
+    fn bounded_store() {
+        let ttl = Duration::from_secs(60);
+        let mut store = HashStore::with_limits(Limits {
+            max_bytes: Some(10),
+            max_entries: Some(3),
+            eviction: Eviction::Lru,
+        });
+        for i in 0..3 {
+            store.put(&Hash::from(i), vec![0; 2], ttl).unwrap();
+        }
+        // The first hash is read, so the second one is the least recently used. Comparing
+        // contents isn't reading them
+        store.get(&Hash::from(0));
+        assert!(store.same_content(&Hash::from(1), &[0; 2]));
+        assert!(!store.same_content(&Hash::from(1), &[0; 3]));
+        store.put(&Hash::from(3), vec![0; 2], ttl).unwrap();
+        assert!(!store.contains(&Hash::from(1)));
+        assert_eq!(
+            store.stats(),
+            Stats {
+                entries: 3,
+                bytes: 6,
+                evictions: 1,
+                evicted_bytes: 2,
+            }
+        );
+
+        // Large contents make room by evicting several hashes
+        store.put(&Hash::from(4), vec![0; 9], ttl).unwrap();
+        assert_eq!(store.list(), vec![Hash::from(4)]);
+        assert_eq!(store.order.borrow().len(), 1);
+        assert_eq!(store.stats().bytes, 9);
+        assert_eq!(store.stats().evictions, 4);
+
+        // Contents that could never fit are refused
+        assert!(store.put(&Hash::from(5), vec![0; 11], ttl).is_err());
+        assert!(store.contains(&Hash::from(4)));
+    }
+
+    #[test]
+    fn lfu_eviction() {
+        let ttl = Duration::from_secs(60);
+        let mut store = HashStore::with_limits(Limits {
+            max_entries: Some(2),
+            eviction: Eviction::Lfu,
+            ..Limits::default()
+        });
+        store.put(&Hash::from(0), vec![0], ttl).unwrap();
+        store.put(&Hash::from(1), vec![1], ttl).unwrap();
+        // The first hash is read more often, even though the second one was read last
+        store.get(&Hash::from(0));
+        store.get(&Hash::from(0));
+        store.get(&Hash::from(1));
+        // Putting the same content again doesn't reset the reads
+        store.put(&Hash::from(0), vec![0], ttl).unwrap();
+        store.put(&Hash::from(2), vec![2], ttl).unwrap();
+        assert!(store.contains(&Hash::from(0)));
+        assert!(!store.contains(&Hash::from(1)));
+        assert!(store.contains(&Hash::from(2)));
+
+        assert_eq!("lfu".parse(), Ok(Eviction::Lfu));
+        assert!("mru".parse::<Eviction>().is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+use std::io;
+use std::str;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::{BufMut, BytesMut};
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use sha1::{Digest, Sha1};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Decoder, Encoder, Framed};
+
+use messages::{Hash, Message};
+
+/// Appended to the key of a client to accept its handshake
+static HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How long the frames of a client may be, in bytes
+/// Clients have nothing to send but pings, and the close frame
+const MAX_FRAME: usize = 4096;
+
+/// How many events wait to be sent to a subscriber
+/// Subscribers too slow to keep up are disconnected, rather than slowing the node down
+const MAX_PENDING: usize = 256;
+
+/// The key accepting the handshake of a client, from its Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// A WebSocket frame
+/// Fragmented messages are not put back together: subscribers ignore what clients send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+impl Frame {
+    fn opcode(&self) -> u8 {
+        match *self {
+            Frame::Text(_) => 0x1,
+            Frame::Binary(_) => 0x2,
+            Frame::Close => 0x8,
+            Frame::Ping(_) => 0x9,
+            Frame::Pong(_) => 0xa,
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        match *self {
+            Frame::Text(ref text) => text.as_bytes(),
+            Frame::Binary(ref data) | Frame::Ping(ref data) | Frame::Pong(ref data) => data,
+            Frame::Close => &[],
+        }
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads the masked frames of a client, and writes unmasked frames to it
+pub struct WebSocketCodec;
+
+impl Decoder for WebSocketCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (fin, opcode) = (buf[0] & 0x80 != 0, buf[0] & 0x0f);
+        if buf[0] & 0x70 != 0 {
+            return Err(invalid("no extension was negotiated"));
+        }
+        if buf[1] & 0x80 == 0 {
+            return Err(invalid("client frames must be masked"));
+        }
+
+        // The length is either in the second byte, or in the 2 or 8 following ones
+        let (offset, len) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (4, u64::from(u16::from_be_bytes([buf[2], buf[3]]))),
+            127 if buf.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (10, u64::from_be_bytes(len))
+            }
+            126 | 127 => return Ok(None),
+            len => (2, u64::from(len)),
+        };
+        if len > MAX_FRAME as u64 {
+            return Err(invalid("frame too long"));
+        }
+        let len = len as usize;
+        if opcode & 0x8 != 0 && (!fin || len > 125) {
+            return Err(invalid("control frames can't be fragmented or longer than 125 bytes"));
+        }
+        if buf.len() < offset + 4 + len {
+            return Ok(None);
+        }
+
+        let head = buf.split_to(offset + 4);
+        let mask = &head[offset..];
+        let payload: Vec<u8> = buf
+            .split_to(len)
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        let frame = match opcode {
+            0x1 => match String::from_utf8(payload) {
+                Ok(text) => Frame::Text(text),
+                Err(_) => return Err(invalid("text frames must be valid UTF-8")),
+            },
+            // Continuation frames carry the rest of a message
+            0x0 | 0x2 => Frame::Binary(payload),
+            0x8 => Frame::Close,
+            0x9 => Frame::Ping(payload),
+            0xa => Frame::Pong(payload),
+            _ => return Err(invalid("unknown opcode")),
+        };
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder for WebSocketCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        let payload = frame.payload();
+        buf.reserve(10 + payload.len());
+        buf.put_u8(0x80 | frame.opcode());
+        if payload.len() < 126 {
+            buf.put_u8(payload.len() as u8);
+        } else if payload.len() <= usize::from(u16::MAX) {
+            buf.put_u8(126);
+            buf.put_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            buf.put_u8(127);
+            buf.put_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        buf.put_slice(payload);
+        Ok(())
+    }
+}
+
+/// The event telling a subscriber about a change, if it is about a watched hash
+/// Changes come from `State::subscribe_changes`, puts as IHAVE and deletions as DELETE
+pub fn event(watched: &Option<HashSet<Hash>>, msg: &Message) -> Option<String> {
+    let (event, hash) = match *msg {
+        Message::IHave(hash) => ("put", hash),
+        Message::Delete(hash) => ("delete", hash),
+        _ => return None,
+    };
+    match *watched {
+        Some(ref watched) if !watched.contains(&hash) => None,
+        _ => Some(format!("{{\"event\":\"{}\",\"hash\":\"{:?}\"}}", event, hash)),
+    }
+}
+
+/// Send the events of the watched hashes (or of all of them) to a subscriber, until it leaves
+/// Changes are read as soon as they come, and queued until the subscriber can take them
+pub fn session<T>(
+    changes: mpsc::Receiver<Message>,
+    watched: Option<HashSet<Hash>>,
+    socket: Framed<T, WebSocketCodec>,
+) -> Box<dyn Future<Item = (), Error = ()>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let (sink, frames) = socket.split();
+    let (mut queue, queued) = mpsc::channel(MAX_PENDING);
+
+    let mut events_queue = queue.clone();
+    let events = changes
+        .filter_map(move |msg| event(&watched, &msg))
+        .for_each(move |event| {
+            events_queue
+                .try_send(Frame::Text(event))
+                .map_err(|_| warn!("Subscriber too slow, disconnecting it"))
+        });
+
+    // Answer pings, and close the session when the client asks
+    let replies = frames
+        .map_err(|e| debug!("Invalid WebSocket frame: {}", e))
+        .for_each(move |frame| {
+            let reply = match frame {
+                Frame::Ping(data) => Frame::Pong(data),
+                Frame::Close => Frame::Close,
+                _ => return Ok(()),
+            };
+            let close = reply == Frame::Close;
+            queue.try_send(reply).map_err(|_| ())?;
+            if close {
+                // Stop reading, the session ends once the close frame is sent
+                return Err(());
+            }
+            Ok(())
+        });
+
+    // The queue ends once both events and replies stop, or with a close frame
+    let sent = queued
+        .take_while(|frame| Ok(*frame != Frame::Close))
+        .forward(sink.sink_map_err(|e| debug!("Could not send to subscriber: {}", e)))
+        .and_then(|(_, sink)| sink.send(Frame::Close))
+        .map(|_| ());
+    let received = events.select(replies).then(|_| Ok(()));
+    Box::new(received.join(sent).map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_key, event, Frame, WebSocketCodec};
+    use std::collections::HashSet;
+    use bytes::BytesMut;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    use messages::{Hash, Message};
+
+    #[test]
+    fn handshake() {
+        // From RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn frames() {
+        // A masked "Hello" from RFC 6455, coming in two parts
+        let mut buf = BytesMut::from(&[0x81, 0x85, 0x37, 0xfa][..]);
+        assert_eq!(WebSocketCodec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&[0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+        let hello = Frame::Text("Hello".to_owned());
+        assert_eq!(WebSocketCodec.decode(&mut buf).unwrap(), Some(hello.clone()));
+        assert_eq!(WebSocketCodec.decode(&mut buf).unwrap(), None);
+
+        // Server frames are not masked
+        WebSocketCodec.encode(hello, &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\x81\x05Hello");
+        buf.clear();
+        WebSocketCodec.encode(Frame::Binary(vec![0; 300]), &mut buf).unwrap();
+        assert_eq!(&buf[..4], &[0x82, 126, 1, 44]);
+        assert_eq!(buf.len(), 304);
+
+        // Unmasked client frames are refused
+        let mut buf = BytesMut::from(&b"\x81\x05Hello"[..]);
+        assert!(WebSocketCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn events() {
+        let hash = Hash::from(42);
+        let all = None;
+        assert_eq!(
+            event(&all, &Message::IHave(hash)),
+            Some("{\"event\":\"put\",\"hash\":\"000000000000002a\"}".to_owned())
+        );
+        assert_eq!(
+            event(&all, &Message::Delete(hash)),
+            Some("{\"event\":\"delete\",\"hash\":\"000000000000002a\"}".to_owned())
+        );
+        assert_eq!(event(&all, &Message::Get(hash)), None);
+
+        let watched: HashSet<_> = [Hash::from(1)].iter().cloned().collect();
+        assert_eq!(event(&Some(watched), &Message::IHave(hash)), None);
+    }
+}